@@ -1,7 +1,15 @@
 #![warn(clippy::unwrap_used)]
+use chrono_tz::Tz;
 use clap::Parser;
 use env_logger::{Env, TimestampPrecision};
-use growattproxy::proxy::{self, GrowattProxyConfig};
+use growattproxy::{
+    dataprocessor,
+    firewall::FirewallPolicy,
+    layouts::{self, InverterType},
+    proxy::{self, GrowattProxyConfig},
+    sink::SinkArgs,
+};
+use std::{path::PathBuf, time::Duration};
 
 #[derive(Parser, Debug)]
 #[clap(name = "growwatproxy", about = "The growatt data upload proxy")]
@@ -24,12 +32,45 @@ struct Opt {
     )]
     growatt_addr: String,
 
-    // set the mqtt addr
-    #[clap(long = "mqtt-addr", env = "GP_MQTT_ADDRESS")]
-    mqtt_addr: Option<String>,
+    #[clap(flatten)]
+    sinks: SinkArgs,
 
-    #[clap(long = "mqtt-port", env = "GP_MQTT_PORT", default_value_t = 1883)]
-    mqtt_port: u16,
+    // acknowledge the inverter data locally instead of forwarding it to the growatt server
+    #[clap(long = "standalone", env = "GP_STANDALONE", default_value_t = false)]
+    standalone: bool,
+
+    // directory used to store inverter data while the growatt server is unreachable
+    #[clap(long = "queue-dir", env = "GP_QUEUE_DIR")]
+    queue_dir: Option<PathBuf>,
+
+    // seconds without inverter data before the inverter is reported offline
+    #[clap(long = "inverter-timeout", env = "GP_INVERTER_TIMEOUT", default_value_t = 600)]
+    inverter_timeout: u64,
+
+    // timezone of the inverter clock, e.g. Europe/Amsterdam
+    #[clap(long = "inverter-timezone", env = "GP_INVERTER_TIMEZONE", default_value = "UTC")]
+    inverter_timezone: Tz,
+
+    // inverter family, selects the storage layouts for the sph and spa inverters
+    #[clap(
+        long = "inverter-type",
+        env = "GP_INVERTER_TYPE",
+        value_enum,
+        default_value = "default"
+    )]
+    inverter_type: InverterType,
+
+    // json file with additional inverter layouts
+    #[clap(long = "layouts", env = "GP_LAYOUTS")]
+    layouts: Option<PathBuf>,
+
+    // serve prometheus metrics on this address, e.g. 0.0.0.0:9090
+    #[clap(long = "metrics-addr", env = "GP_METRICS_ADDRESS")]
+    metrics_addr: Option<String>,
+
+    // policy for the commands the growatt server sends to the inverter, e.g. "write:3=allow,write=drop,*=log"
+    #[clap(long = "firewall", env = "GP_FIREWALL")]
+    firewall: Option<FirewallPolicy>,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -40,11 +81,24 @@ async fn main() {
         .format_timestamp(Some(TimestampPrecision::Millis))
         .init();
 
+    dataprocessor::set_inverter_timezone(opt.inverter_timezone).expect("Failed to set the inverter timezone");
+    layouts::set_inverter_type(opt.inverter_type).expect("Failed to set the inverter type");
+    if let Some(path) = &opt.layouts {
+        layouts::load_layouts(path).expect("Failed to load the layouts");
+    }
+
     let cfg = GrowattProxyConfig {
         listen_address: opt.addr,
         growatt_address: opt.growatt_addr,
-        mqtt_address: opt.mqtt_addr,
-        mqtt_port: opt.mqtt_port,
+        sinks: opt
+            .sinks
+            .registry("pvpanelendak/PUB/CH1", "remi")
+            .expect("Failed to start the outputs"),
+        standalone: opt.standalone,
+        queue_dir: opt.queue_dir,
+        inverter_timeout: Duration::from_secs(opt.inverter_timeout),
+        metrics_address: opt.metrics_addr,
+        firewall: opt.firewall,
     };
 
     log::debug!("Run server on: {}", cfg.listen_address);
@@ -1,5 +1,8 @@
 #![warn(clippy::unwrap_used)]
+use chrono_tz::Tz;
 use clap::Parser;
+use growattproxy::{layouts::InverterType, sink::SinkArgs};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[clap(name = "growwatsniffer", about = "The growatt data sniffer")]
@@ -12,22 +15,35 @@ struct Opt {
     #[clap(short = 'p', long = "port", default_value_t = 5279)]
     port: u16,
 
-    // set the mqtt addr
-    #[clap(long = "mqtt-addr")]
-    mqtt_addr: Option<String>,
-
-    #[clap(long = "mqtt-port", default_value_t = 1883)]
-    mqtt_port: u16,
+    #[clap(flatten)]
+    sinks: SinkArgs,
 
     #[clap(short = 'd', long = "dump-packets", default_value_t = false)]
     dump_packets: bool,
+
+    // timezone of the inverter clock, e.g. Europe/Amsterdam
+    #[clap(long = "inverter-timezone", env = "GP_INVERTER_TIMEZONE", default_value = "UTC")]
+    inverter_timezone: Tz,
+
+    // inverter family, selects the storage layouts for the sph and spa inverters
+    #[clap(
+        long = "inverter-type",
+        env = "GP_INVERTER_TYPE",
+        value_enum,
+        default_value = "default"
+    )]
+    inverter_type: InverterType,
+
+    // json file with additional inverter layouts
+    #[clap(long = "layouts", env = "GP_LAYOUTS")]
+    layouts: Option<PathBuf>,
 }
 
 fn main() {
     #[cfg(feature = "sniffer")]
     {
         use env_logger::{Env, TimestampPrecision};
-        use growattproxy::{mqtt::MqttConfig, sniffer::GrowattSnifferConfig};
+        use growattproxy::sniffer::GrowattSnifferConfig;
         let opt = Opt::parse();
 
         env_logger::Builder::from_env(Env::default().default_filter_or("debug"))
@@ -35,22 +51,25 @@ fn main() {
             .init();
 
         log::info!("Sniff sniff");
-        let mqtt_config;
-        if let Some(addr) = opt.mqtt_addr {
-            mqtt_config = Some(MqttConfig {
-                server: addr,
-                port: opt.mqtt_port,
-            });
-        } else {
-            mqtt_config = None;
+        growattproxy::dataprocessor::set_inverter_timezone(opt.inverter_timezone)
+            .expect("Failed to set the inverter timezone");
+        growattproxy::layouts::set_inverter_type(opt.inverter_type).expect("Failed to set the inverter type");
+        if let Some(path) = &opt.layouts {
+            growattproxy::layouts::load_layouts(path).expect("Failed to load the layouts");
         }
+        let sinks = opt
+            .sinks
+            .registry("energy/growattproxy", "fields")
+            .expect("Failed to start the outputs");
 
-        growattproxy::sniffer::sniff(&GrowattSnifferConfig {
+        if let Err(err) = growattproxy::sniffer::sniff(&GrowattSnifferConfig {
             address: opt.addr,
             port: opt.port,
-            mqtt: mqtt_config,
+            sinks,
             dump_packets: opt.dump_packets,
-        });
+        }) {
+            log::error!("Sniffer stopped: {err}");
+        }
     }
 
     #[cfg(not(feature = "sniffer"))]
@@ -0,0 +1,267 @@
+use std::{collections::BTreeMap, ops::RangeInclusive, str::FromStr, sync::Mutex};
+
+use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    dataprocessor::{GrowattData, HEADER_SIZE},
+    framing::{self, GrowattFrame, MSG_READ_REGISTERS, MSG_WRITE_REGISTER, SERIAL_LENGTH},
+    message::GrowattMessage,
+    ProxyError,
+};
+
+// the inverter holding registers that can be read with a single request
+const MAX_READ_COUNT: u16 = 125;
+
+/// A register request sent to the inverter on behalf of a command topic message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    ReadRegisters { start: u16, end: u16 },
+    WriteRegister { register: u16, value: u16 },
+}
+
+impl Command {
+    /// Parses a command message: `{"command": "read", "register": 3, "count": 2}` or
+    /// `{"command": "write", "register": 3, "value": 100}`
+    pub fn parse(payload: &[u8]) -> Result<Command, ProxyError> {
+        let json: Value = serde_json::from_slice(payload)
+            .map_err(|err| ProxyError::RuntimeError(format!("Invalid command: {err}")))?;
+
+        let number = |key: &str| -> Result<Option<u16>, ProxyError> {
+            match json.get(key) {
+                None => Ok(None),
+                Some(value) => value
+                    .as_u64()
+                    .and_then(|value| u16::try_from(value).ok())
+                    .map(Some)
+                    .ok_or_else(|| ProxyError::RuntimeError(format!("'{key}' must be a register number"))),
+            }
+        };
+        let register = number("register")?.ok_or(ProxyError::RuntimeError(String::from("'register' is required")))?;
+
+        match json.get("command").and_then(Value::as_str) {
+            Some("read") => {
+                let count = number("count")?.unwrap_or(1);
+                if !(1..=MAX_READ_COUNT).contains(&count) || register.checked_add(count - 1).is_none() {
+                    return Err(ProxyError::RuntimeError(format!("Invalid register count {count}")));
+                }
+
+                Ok(Command::ReadRegisters {
+                    start: register,
+                    end: register + (count - 1),
+                })
+            }
+            Some("write") => Ok(Command::WriteRegister {
+                register,
+                value: number("value")?.ok_or(ProxyError::RuntimeError(String::from("'value' is required")))?,
+            }),
+            _ => Err(ProxyError::RuntimeError(String::from(
+                "'command' must be \"read\" or \"write\"",
+            ))),
+        }
+    }
+
+    /// The message type and first register of the request, the response repeats them
+    pub fn key(&self) -> (u8, u16) {
+        match self {
+            Command::ReadRegisters { start, .. } => (MSG_READ_REGISTERS, *start),
+            Command::WriteRegister { register, .. } => (MSG_WRITE_REGISTER, *register),
+        }
+    }
+
+    pub fn frame(&self, datalogger: &Datalogger, packet_index: u16) -> Result<Vec<u8>, ProxyError> {
+        let (message_type, payload) = match self {
+            Command::ReadRegisters { start, end } => (MSG_READ_REGISTERS, [start.to_be_bytes(), end.to_be_bytes()]),
+            Command::WriteRegister { register, value } => {
+                (MSG_WRITE_REGISTER, [register.to_be_bytes(), value.to_be_bytes()])
+            }
+        };
+
+        GrowattFrame::new(message_type)
+            .packet_index(packet_index)
+            .protocol(datalogger.protocol)
+            .device(datalogger.device)
+            .serial(&datalogger.serial)
+            .payload(&payload.concat())
+            .build()
+    }
+}
+
+/// The key of the command a response answers, see `Command::key`
+pub fn response_key(message: &GrowattMessage) -> Option<(u8, u16)> {
+    match message {
+        GrowattMessage::ReadRegistersResponse { start, .. } => Some((MSG_READ_REGISTERS, *start)),
+        GrowattMessage::WriteRegisterResponse { register, .. } => Some((MSG_WRITE_REGISTER, *register)),
+        _ => None,
+    }
+}
+
+/// The json published for the response of the inverter to a command
+pub fn response_json(message: &GrowattMessage) -> Value {
+    match message {
+        GrowattMessage::ReadRegistersResponse { start, values } => json!({"register": start, "values": values}),
+        GrowattMessage::WriteRegisterResponse { register, status } => json!({"register": register, "status": status}),
+        other => json!({"error": format!("unexpected {other}")}),
+    }
+}
+
+/// The datalogger that sends the inverter frames, the commands are addressed to it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Datalogger {
+    pub serial: String,
+    pub protocol: u8,
+    pub device: u8,
+}
+
+impl Datalogger {
+    pub fn from_frame(frame: &[u8]) -> Option<Datalogger> {
+        if frame.len() < HEADER_SIZE + SERIAL_LENGTH {
+            return None;
+        }
+
+        let mut data = frame[..HEADER_SIZE + SERIAL_LENGTH].to_vec();
+        if framing::has_crc(frame) {
+            GrowattData::decrypt(&mut data);
+        }
+
+        let serial = std::str::from_utf8(&data[HEADER_SIZE..]).ok()?.trim_end_matches('\0');
+        Some(Datalogger {
+            serial: String::from(serial),
+            protocol: frame[3],
+            device: frame[6],
+        })
+    }
+}
+
+/// The registers that may be written, e.g. "3,122,300-305"
+#[derive(Clone, Debug, Default)]
+pub struct RegisterAllowList {
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl RegisterAllowList {
+    pub fn allows(&self, register: u16) -> bool {
+        self.ranges.iter().any(|range| range.contains(&register))
+    }
+}
+
+impl FromStr for RegisterAllowList {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let ranges = value
+            .split(',')
+            .filter(|part| !part.trim().is_empty())
+            .map(parse_registers)
+            .collect::<Result<_, _>>()?;
+
+        Ok(RegisterAllowList { ranges })
+    }
+}
+
+/// Parses a register or an inclusive register range: "3" or "300-305"
+pub fn parse_registers(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let register = |value: &str| {
+        value
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| format!("invalid register '{value}'"))
+    };
+
+    match value.split_once('-') {
+        Some((start, end)) => Ok(register(start)?..=register(end)?),
+        None => register(value).map(|register| register..=register),
+    }
+}
+
+/// Hands the commands received over MQTT to the session of the inverter
+pub struct CommandRouter {
+    // command channel by inverter serial
+    sessions: Mutex<BTreeMap<String, UnboundedSender<Command>>>,
+}
+
+static ROUTER: CommandRouter = CommandRouter {
+    sessions: Mutex::new(BTreeMap::new()),
+};
+
+pub fn router() -> &'static CommandRouter {
+    &ROUTER
+}
+
+impl CommandRouter {
+    pub fn register(&self, serial: &str, sender: UnboundedSender<Command>) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.insert(String::from(serial), sender);
+        }
+    }
+
+    /// Removes the session, unless the inverter already reconnected with a new session
+    pub fn unregister(&self, serial: &str, sender: &UnboundedSender<Command>) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            if sessions.get(serial).is_some_and(|current| current.same_channel(sender)) {
+                sessions.remove(serial);
+            }
+        }
+    }
+
+    /// True while the session handles the inverter, false once the inverter reconnected with a new session
+    pub fn is_registered(&self, serial: &str, sender: &UnboundedSender<Command>) -> bool {
+        self.sessions
+            .lock()
+            .is_ok_and(|sessions| sessions.get(serial).is_some_and(|current| current.same_channel(sender)))
+    }
+
+    pub fn send(&self, serial: &str, command: Command) -> Result<(), ProxyError> {
+        let sessions = self
+            .sessions
+            .lock()
+            .map_err(|_| ProxyError::RuntimeError(String::from("Command router lock poisoned")))?;
+
+        sessions
+            .get(serial)
+            .and_then(|sender| sender.send(command).ok())
+            .ok_or_else(|| ProxyError::RuntimeError(format!("Inverter {serial} is not connected")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{router, Command, Datalogger, RegisterAllowList};
+    use crate::message::{Direction, GrowattMessage};
+
+    #[test]
+    fn command_frames() {
+        assert_eq!(
+            Command::parse(br#"{"command": "read", "register": 3, "count": 2}"#).unwrap(),
+            Command::ReadRegisters { start: 3, end: 4 }
+        );
+        assert!(Command::parse(br#"{"command": "read", "register": 3, "count": 0}"#).is_err());
+        assert!(Command::parse(br#"{"command": "write", "register": 3}"#).is_err());
+        assert!(Command::parse(br#"{"command": "write", "register": 3, "value": 70000}"#).is_err());
+
+        let allowed: RegisterAllowList = "3, 122,300-305".parse().unwrap();
+        assert!(allowed.allows(3) && allowed.allows(122) && allowed.allows(302));
+        assert!(!allowed.allows(4) && !allowed.allows(306));
+        assert!("3-x".parse::<RegisterAllowList>().is_err());
+
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        let datalogger = Datalogger::from_frame(growatt_data).unwrap();
+
+        let command = Command::parse(br#"{"command": "write", "register": 3, "value": 80}"#).unwrap();
+        let frame = command.frame(&datalogger, 9).unwrap();
+        assert_eq!(frame[..2], [0, 9]);
+        assert_eq!(
+            GrowattMessage::decode(&frame, Direction::FromServer).unwrap(),
+            GrowattMessage::WriteRegister { register: 3, value: 80 }
+        );
+
+        // a reconnected inverter replaces the stale session
+        let (stale, _) = tokio::sync::mpsc::unbounded_channel();
+        let (current, _) = tokio::sync::mpsc::unbounded_channel();
+        router().register("TEST000001", stale.clone());
+        router().register("TEST000001", current.clone());
+        assert!(!router().is_registered("TEST000001", &stale));
+        router().unregister("TEST000001", &stale);
+        assert!(router().is_registered("TEST000001", &current));
+    }
+}
@@ -1,23 +1,88 @@
-use chrono::{DateTime, Utc};
-use std::iter::zip;
+use chrono::{DateTime, LocalResult, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::{iter::zip, sync::OnceLock};
 
 use crc16::{State, MODBUS};
 use num_rational::Rational64;
 
 use crate::{
+    framing,
     layouts::{self},
-    ProxyError,
+    message, status, ProxyError,
 };
 
-const HEADER_SIZE: usize = 8;
+pub const HEADER_SIZE: usize = 8;
 const MAX_PV_POWER: f64 = 8000.0;
+// year, month, day, hour, minute and second
+const DATE_LENGTH: usize = 6;
+const SERIAL_OFFSET: usize = 38;
+
+// timezone of the inverter clock
+static INVERTER_TIMEZONE: OnceLock<Tz> = OnceLock::new();
+
+/// Sets the timezone of the inverter clock used to decode the date fields, UTC when not set.
+/// The timezone can only be set once.
+pub fn set_inverter_timezone(tz: Tz) -> Result<(), ProxyError> {
+    INVERTER_TIMEZONE
+        .set(tz)
+        .map_err(|_| ProxyError::RuntimeError(String::from("Inverter timezone already set")))
+}
+
+/// Decodes the inverter clock bytes (year since 2000, month, day, hour, minute, second) in the given timezone.
+/// Inverters without a synchronized clock send all zeros, the current time is used for those.
+pub fn decode_date(data: &[u8], tz: Tz) -> Result<DateTime<Utc>, ProxyError> {
+    let invalid = || ProxyError::RuntimeError(format!("Invalid date: {data:?}"));
+
+    let [year, month, day, hour, minute, second]: [u8; DATE_LENGTH] = data.try_into().map_err(|_| invalid())?;
+    if data.iter().all(|b| *b == 0) {
+        return Ok(Utc::now());
+    }
+
+    let local = NaiveDate::from_ymd_opt(2000 + year as i32, month as u32, day as u32)
+        .and_then(|date| date.and_hms_opt(hour as u32, minute as u32, second as u32))
+        .ok_or_else(invalid)?;
+
+    match tz.from_local_datetime(&local) {
+        // the first occurrence is used for the repeated hour when the clock goes back
+        LocalResult::Single(date) | LocalResult::Ambiguous(date, _) => Ok(date.with_timezone(&Utc)),
+        LocalResult::None => Err(invalid()),
+    }
+}
 
+fn read_unsigned(data: &[u8]) -> Result<u64, ProxyError> {
+    Ok(match data.len() {
+        1 => data[0] as u64,
+        2 => u16::from_be_bytes(data.try_into()?) as u64,
+        4 => u32::from_be_bytes(data.try_into()?) as u64,
+        8 => u64::from_be_bytes(data.try_into()?),
+        length => return Err(ProxyError::RuntimeError(format!("Invalid length for number: {length}"))),
+    })
+}
+
+fn read_signed(data: &[u8]) -> Result<i64, ProxyError> {
+    Ok(match data.len() {
+        1 => data[0] as i8 as i64,
+        2 => i16::from_be_bytes(data.try_into()?) as i64,
+        4 => i32::from_be_bytes(data.try_into()?) as i64,
+        8 => i64::from_be_bytes(data.try_into()?),
+        length => return Err(ProxyError::RuntimeError(format!("Invalid length for number: {length}"))),
+    })
+}
+
+#[derive(Clone)]
 pub enum FieldType {
     Text,
     Date,
     Number(i64),
+    /// Two's complement number
+    SignedNumber(i64),
+    /// Names of the set bits, bit 0 is the least significant bit
+    Bitfield(Vec<(u32, String)>),
+    /// Name of the value
+    Enum(Vec<(i64, String)>),
 }
 
+#[derive(Clone)]
 pub struct FieldSpecification {
     name: String,
     offset: usize,
@@ -79,7 +144,7 @@ impl FieldSpecification {
         FieldSpecification {
             name: name.to_string(),
             offset: offset / 2,
-            length: 12,
+            length: DATE_LENGTH,
             field_type: FieldType::Date,
         }
     }
@@ -92,8 +157,36 @@ impl FieldSpecification {
             field_type: FieldType::Number(divide),
         }
     }
+
+    pub fn signed_number(name: &str, offset: usize, length: usize, divide: i64) -> FieldSpecification {
+        FieldSpecification {
+            name: name.to_string(),
+            offset: offset / 2,
+            length,
+            field_type: FieldType::SignedNumber(divide),
+        }
+    }
+
+    pub fn bitfield(name: &str, offset: usize, length: usize, bits: &[(u32, &str)]) -> FieldSpecification {
+        FieldSpecification {
+            name: name.to_string(),
+            offset: offset / 2,
+            length,
+            field_type: FieldType::Bitfield(bits.iter().map(|(bit, name)| (*bit, name.to_string())).collect()),
+        }
+    }
+
+    pub fn enumeration(name: &str, offset: usize, length: usize, values: &[(i64, &str)]) -> FieldSpecification {
+        FieldSpecification {
+            name: name.to_string(),
+            offset: offset / 2,
+            length,
+            field_type: FieldType::Enum(values.iter().map(|(value, name)| (*value, name.to_string())).collect()),
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct LayoutSpecification {
     id: String,
     decrypt: bool,
@@ -137,24 +230,19 @@ impl GrowattData {
     }
 
     pub fn packet_index(&self) -> u16 {
-        u16::from_be_bytes(self.header[0..2].try_into().unwrap())
+        u16::from_be_bytes([self.header[0], self.header[1]])
     }
 
     pub fn is_buffered(&self) -> bool {
-        self.header[7] == 0x50
+        self.header[7] == framing::MSG_BUFFERED_DATA
     }
 
-    fn is_smart_meter(&self) -> bool {
-        self.header[7] == 0x20 || self.header[7] == 0x1b
+    pub fn is_smart_meter(&self) -> bool {
+        self.header[7] == framing::MSG_SMART_METER || self.header[7] == framing::MSG_BUFFERED_SMART_METER
     }
 
     pub fn layout(&self) -> String {
-        let mut layout = format!("T{:02x}{:02x}{:02x}", self.header[3], self.header[6], self.header[7]);
-        if self.is_smart_meter() {
-            layout.push('X');
-        }
-
-        layout
+        layouts::layout_id(&self.header)
     }
 
     pub fn has_data(&self) -> bool {
@@ -165,8 +253,52 @@ impl GrowattData {
         self.fields.len()
     }
 
+    /// The inverter serial number, when the layout contains it
+    pub fn serial(&self) -> Option<String> {
+        match self.field_value("pvserial") {
+            Some(FieldValue::Text(serial)) => Some(serial.trim_end_matches('\0').to_string()),
+            _ => None,
+        }
+    }
+
+    /// Identifies the device that produced the data, the smart meter is a separate device of the inverter
+    pub fn device_id(&self) -> Option<String> {
+        let serial = self.serial()?;
+        if self.is_smart_meter() {
+            Some(format!("{serial}_meter"))
+        } else {
+            Some(serial)
+        }
+    }
+
     pub fn field_value(&self, name: &str) -> Option<FieldValue> {
-        return self.fields.iter().find(|&f| f.name == name).map(|f| f.value.clone());
+        self.fields.iter().find(|&f| f.name == name).map(|f| f.value.clone())
+    }
+
+    /// The decoded operating state, see `status::status_text`
+    pub fn status(&self) -> Option<String> {
+        match self.field_value("pvstatustext") {
+            Some(FieldValue::Text(status)) => Some(status),
+            _ => None,
+        }
+    }
+
+    // Adds the status, fault and warning registers as text
+    fn add_status_fields(&mut self) {
+        let number = |name| match self.field_value(name) {
+            Some(FieldValue::Number(num)) if num.is_integer() => Some(num.to_integer()),
+            _ => None,
+        };
+        let storage = matches!(self.layout_spec.as_str(), "T06NNNNXSPH" | "T06NNNNXSPA");
+
+        let texts = [
+            number("pvstatus").map(|value| ("pvstatustext", status::status_text(value, storage))),
+            number("pvfaultcode").map(|code| ("pvfaulttext", status::fault_text(code))),
+            number("pvwarningcode").map(|code| ("pvwarningtext", status::warning_text(code))),
+        ];
+        for (name, text) in texts.into_iter().flatten() {
+            self.add_text_field(name, &text);
+        }
     }
 
     fn add_text_field(&mut self, name: &str, value: &str) {
@@ -181,7 +313,57 @@ impl GrowattData {
         self.fields.push(Field::number(name, value));
     }
 
-    fn decrypt(growatt_data: &mut [u8]) {
+    fn add_field(&mut self, field: &FieldSpecification, data: &[u8]) -> Result<(), ProxyError> {
+        match field.field_type {
+            FieldType::Text => {
+                let val = std::str::from_utf8(data)?;
+                self.add_text_field(field.name.as_str(), val);
+            }
+
+            FieldType::Date => {
+                let date = decode_date(data, INVERTER_TIMEZONE.get().copied().unwrap_or(Tz::UTC))?;
+                self.add_date_field(field.name.as_str(), date);
+            }
+
+            FieldType::Number(divide) => {
+                let val = i64::try_from(read_unsigned(data)?)
+                    .map_err(|_| ProxyError::RuntimeError(format!("Number out of range: {}", field.name)))?;
+
+                assert!(divide != 0);
+                self.add_number_field(field.name.as_str(), Rational64::new(val, divide));
+            }
+
+            FieldType::SignedNumber(divide) => {
+                assert!(divide != 0);
+                self.add_number_field(field.name.as_str(), Rational64::new(read_signed(data)?, divide));
+            }
+
+            FieldType::Bitfield(ref bits) => {
+                let val = read_unsigned(data)?;
+                let names: Vec<String> = (0..data.len() as u32 * 8)
+                    .filter(|bit| val & (1 << bit) != 0)
+                    .map(|bit| match bits.iter().find(|(flag, _)| *flag == bit) {
+                        Some((_, name)) => name.clone(),
+                        None => format!("bit {bit}"),
+                    })
+                    .collect();
+                self.add_text_field(field.name.as_str(), &names.join(", "));
+            }
+
+            FieldType::Enum(ref values) => {
+                let val = read_unsigned(data)? as i64;
+                let text = match values.iter().find(|(value, _)| *value == val) {
+                    Some((_, name)) => name.clone(),
+                    None => format!("unknown ({val})"),
+                };
+                self.add_text_field(field.name.as_str(), &text);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn decrypt(growatt_data: &mut [u8]) {
         static MASK: &[u8; 7] = b"Growatt";
 
         // decrypt the data
@@ -190,7 +372,12 @@ impl GrowattData {
         }
     }
 
-    fn validate_integity(data: &[u8]) -> Result<(), ProxyError> {
+    /// Applies the Growatt payload mask, masking is symmetric so this is the inverse of the decryption
+    pub(crate) fn encrypt(growatt_data: &mut [u8]) {
+        GrowattData::decrypt(growatt_data);
+    }
+
+    pub(crate) fn validate_integity(data: &[u8]) -> Result<(), ProxyError> {
         let size = data.len();
         let header_payload_length = u16::from_be_bytes(data[4..6].try_into()?) as usize;
         let actual_payload_length = size - HEADER_SIZE;
@@ -207,9 +394,7 @@ impl GrowattData {
             let actual_crc = State::<MODBUS>::calculate(&data[..size - 2]);
 
             if header_crc != actual_crc {
-                return Err(ProxyError::RuntimeError(format!(
-                    "Crc mismatch: expected {header_crc} got {actual_crc}"
-                )));
+                return Err(ProxyError::CrcError(format!("expected {header_crc} got {actual_crc}")));
             }
 
             log::debug!("CRC Matched!");
@@ -231,7 +416,7 @@ impl GrowattData {
         result.header = growatt_data[0..8].try_into()?;
 
         log::info!("Header: {} #{}", result.layout(), result.packet_index());
-        if let Err(_) = GrowattData::validate_integity(growatt_data) {
+        if GrowattData::validate_integity(growatt_data).is_err() {
             log::warn!("Packet already decrypted");
         } else {
             GrowattData::decrypt(growatt_data);
@@ -239,7 +424,7 @@ impl GrowattData {
 
         let mut offset = None;
         if let Some(serial) = serial {
-            offset = find_subsequence(&growatt_data, serial.as_bytes());
+            offset = find_subsequence(growatt_data, serial.as_bytes());
             if let Some(offset) = offset {
                 log::info!("Serial found at offset: {}", offset);
 
@@ -274,7 +459,8 @@ impl GrowattData {
             }
         }
 
-        let spec = layouts::t06nnnnx(offset.unwrap_or(0));
+        // the layout fields are shifted by the distance to the expected serial position
+        let spec = layouts::t06nnnnx(offset.map_or(0, |offset| offset.saturating_sub(SERIAL_OFFSET)));
         let data = GrowattData::from_buffer(growatt_data, &spec)?;
 
         for field in &data.fields {
@@ -304,7 +490,7 @@ impl GrowattData {
 
     pub fn from_buffer_auto_detect_layout(
         growatt_data: &mut [u8],
-        serial: Option<String>,
+        _serial: Option<String>,
     ) -> Result<GrowattData, ProxyError> {
         if growatt_data.len() < 12 {
             // ACK message
@@ -317,8 +503,7 @@ impl GrowattData {
 
         GrowattData::validate_integity(growatt_data)?;
 
-        let layout = result.layout();
-        // if layout == "T065103" {
+        // if result.layout() == "T065103" {
         //     let datetime: DateTime<Utc> = SystemTime::now().into();
         //     if let Err(err) = dump_packet(
         //         &growatt_data,
@@ -332,49 +517,21 @@ impl GrowattData {
             GrowattData::decrypt(growatt_data);
         }
 
-        if layout == "T065103" || layout == "T065129" {
-            // ignore these layouts that do not contain power data
+        if !message::carries_data(result.header[7]) {
+            // announces and the command messages do not contain power data
             return Ok(result);
         }
 
         for field in &spec.fields {
-            if field.offset + field.length >= growatt_data.len() {
+            let offset = field.offset + spec.offset;
+            if offset + field.length >= growatt_data.len() {
                 log::debug!("Field '{}' out of range", field.name);
                 continue;
             }
 
-            let data_slice = &growatt_data[field.offset..field.offset + field.length];
-            match field.field_type {
-                FieldType::Text => {
-                    let val = std::str::from_utf8(&data_slice)?;
-                    result.add_text_field(field.name.as_str(), val);
-                }
-
-                FieldType::Date => {
-                    //result.add_text_field(field.name.as_str(), val);
-                    result.add_date_field(field.name.as_str(), Utc::now());
-                }
-
-                FieldType::Number(divide) => {
-                    let val: i64;
-                    if field.length == 1 {
-                        val = u8::from_be_bytes(data_slice.try_into().expect("Invalid u8 length")) as i64;
-                    } else if field.length == 2 {
-                        val = u16::from_be_bytes(data_slice.try_into().expect("Invalid u16 length")) as i64;
-                    } else if field.length == 4 {
-                        val = u32::from_be_bytes(data_slice.try_into().expect("Invalid u32 length")) as i64;
-                    } else {
-                        return Err(ProxyError::RuntimeError(format!(
-                            "Invalid length for number: {}",
-                            field.length
-                        )));
-                    }
-
-                    assert!(divide != 0);
-                    result.add_number_field(field.name.as_str(), Rational64::new(val, divide));
-                }
-            }
+            result.add_field(field, &growatt_data[offset..offset + field.length])?;
         }
+        result.add_status_fields();
 
         if let Some(FieldValue::Number(val)) = result.field_value("pvpowerout") {
             let float_val: f64 = *val.numer() as f64 / *val.denom() as f64;
@@ -404,38 +561,14 @@ impl GrowattData {
         }
 
         for field in &spec.fields {
-            let data_slice = &growatt_data[field.offset..field.offset + field.length];
-            match field.field_type {
-                FieldType::Text => {
-                    let val = std::str::from_utf8(&data_slice)?;
-                    result.add_text_field(field.name.as_str(), val);
-                }
-
-                FieldType::Date => {
-                    //result.add_text_field(field.name.as_str(), val);
-                    result.add_date_field(field.name.as_str(), Utc::now());
-                }
-
-                FieldType::Number(divide) => {
-                    let val: i64;
-                    if field.length == 1 {
-                        val = u8::from_be_bytes(data_slice.try_into().expect("Invalid u8 length")) as i64;
-                    } else if field.length == 2 {
-                        val = u16::from_be_bytes(data_slice.try_into().expect("Invalid u16 length")) as i64;
-                    } else if field.length == 4 {
-                        val = u32::from_be_bytes(data_slice.try_into().expect("Invalid u32 length")) as i64;
-                    } else {
-                        return Err(ProxyError::RuntimeError(format!(
-                            "Invalid length for number: {}",
-                            field.length
-                        )));
-                    }
-
-                    assert!(divide != 0);
-                    result.add_number_field(field.name.as_str(), Rational64::new(val, divide));
-                }
+            let offset = field.offset + spec.offset;
+            if offset + field.length > growatt_data.len() {
+                return Err(ProxyError::RuntimeError(format!("Field '{}' out of range", field.name)));
             }
+
+            result.add_field(field, &growatt_data[offset..offset + field.length])?;
         }
+        result.add_status_fields();
 
         Ok(result)
     }
@@ -443,15 +576,17 @@ impl GrowattData {
 
 #[cfg(test)]
 mod tests {
+    use chrono::{TimeZone, Utc};
+    use crc16::{State, MODBUS};
     use num_rational::Rational64;
 
     use crate::dataprocessor::FieldValue;
-    use crate::layouts;
+    use crate::{framing, layouts};
 
-    use super::GrowattData;
+    use super::{decode_date, FieldSpecification, GrowattData};
 
     fn init() {
-        let _ = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
+        let _ = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).try_init();
     }
 
     #[test]
@@ -471,6 +606,7 @@ mod tests {
             gd.field_value("pvstatus").unwrap(),
             FieldValue::Number(Rational64::from_integer(1))
         );
+        assert_eq!(gd.status().unwrap(), "Normal");
 
         assert_eq!(
             gd.field_value("pvpowerin").unwrap(),
@@ -487,6 +623,12 @@ mod tests {
 
         if let Some(FieldValue::Text(serial)) = gd.field_value("pvserial") {
             assert_eq!(serial, String::from("MFK0CE306Q"));
+            // the announce layout is ignored by the auto detection, parse the same payload as a data frame
+            let mut data = growatt_data.to_vec();
+            data[7] = 0x04;
+            let crc = State::<MODBUS>::calculate(&data[..data.len() - 2]);
+            let end = data.len();
+            data[end - 2..].copy_from_slice(&crc.to_be_bytes());
             let gd_serial = GrowattData::from_buffer_auto_detect_layout(&mut data, Some(serial)).unwrap();
 
             assert_eq!(
@@ -497,4 +639,76 @@ mod tests {
             panic!("No serial found");
         }
     }
+
+    #[test]
+    fn packet_date() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        let mut data = growatt_data.to_vec();
+        GrowattData::decrypt(&mut data);
+        data[68..74].copy_from_slice(&[23, 3, 1, 10, 52, 14]);
+        GrowattData::encrypt(&mut data);
+        framing::update_crc(&mut data);
+
+        let gd = GrowattData::from_buffer(&mut data, &layouts::t065004x()).unwrap();
+        assert_eq!(
+            gd.field_value("date").unwrap(),
+            FieldValue::Date(Utc.with_ymd_and_hms(2023, 3, 1, 10, 52, 14).unwrap())
+        );
+
+        // local time of the inverter, summer time in Amsterdam is UTC+2
+        assert_eq!(
+            decode_date(&[23, 7, 1, 12, 0, 0], chrono_tz::Europe::Amsterdam).unwrap(),
+            Utc.with_ymd_and_hms(2023, 7, 1, 10, 0, 0).unwrap()
+        );
+
+        assert!(decode_date(&[23, 13, 1, 12, 0, 0], chrono_tz::UTC).is_err());
+        assert!(decode_date(&[23, 3, 26, 2, 30, 0], chrono_tz::Europe::Amsterdam).is_err());
+    }
+
+    #[test]
+    fn field_types() {
+        let mut gd = GrowattData::new("test");
+        let specs = [
+            FieldSpecification::signed_number("signed16", 0, 2, 10),
+            FieldSpecification::signed_number("signed32", 0, 4, 1),
+            FieldSpecification::number("unsigned64", 0, 8, 10),
+            FieldSpecification::bitfield("faults", 0, 2, &[(0, "grid"), (15, "fan")]),
+            FieldSpecification::enumeration("mode", 0, 1, &[(1, "Normal"), (3, "Fault")]),
+        ];
+        let data: [&[u8]; 5] = [
+            &(-123i16).to_be_bytes(),
+            &(-70000i32).to_be_bytes(),
+            &12345678901u64.to_be_bytes(),
+            &0x8005u16.to_be_bytes(),
+            &[3],
+        ];
+        for (spec, data) in specs.iter().zip(data) {
+            gd.add_field(spec, data).unwrap();
+        }
+
+        assert_eq!(
+            gd.field_value("signed16").unwrap(),
+            FieldValue::Number(Rational64::new(-123, 10))
+        );
+        assert_eq!(
+            gd.field_value("signed32").unwrap(),
+            FieldValue::Number(Rational64::from_integer(-70000))
+        );
+        assert_eq!(
+            gd.field_value("unsigned64").unwrap(),
+            FieldValue::Number(Rational64::new(12345678901, 10))
+        );
+        assert_eq!(
+            gd.field_value("faults").unwrap(),
+            FieldValue::Text(String::from("grid, bit 2, fan"))
+        );
+        assert_eq!(gd.field_value("mode").unwrap(), FieldValue::Text(String::from("Fault")));
+
+        assert!(gd
+            .add_field(&FieldSpecification::number("huge", 0, 8, 1), &[0xff; 8])
+            .is_err());
+        assert!(gd
+            .add_field(&FieldSpecification::number("odd", 0, 3, 1), &[0; 3])
+            .is_err());
+    }
 }
@@ -0,0 +1,205 @@
+use std::{ops::RangeInclusive, str::FromStr};
+
+use crate::{
+    command,
+    framing::{MSG_READ_DATALOGGER, MSG_READ_REGISTERS, MSG_SET_DATALOGGER, MSG_WRITE_REGISTER, MSG_WRITE_REGISTERS},
+    message::{GrowattMessage, DATALOGGER_TIME_REGISTER},
+};
+
+/// What happens with a command the Growatt server sends to the inverter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    /// Forward the command and log it
+    Log,
+    Drop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Rule {
+    // command name, see `command_name`, or '*' for every command
+    command: String,
+    registers: Option<RangeInclusive<u16>>,
+    action: Action,
+}
+
+/// Rules for the commands of the server, the first matching rule applies and commands without a matching rule are
+/// allowed. The policy is a comma separated list of `<command>[:<register>[-<register>]]=<action>`, e.g.
+/// "write:3=allow,write=drop,datalogger-write=drop,*=log".
+#[derive(Clone, Debug, Default)]
+pub struct FirewallPolicy {
+    rules: Vec<Rule>,
+}
+
+// The rule name and register range of the messages the server sends on its own, None for the acks and pings
+fn command_name(message: &GrowattMessage) -> Option<(&'static str, Option<RangeInclusive<u16>>)> {
+    Some(match message {
+        GrowattMessage::ReadRegisters { start, end } => ("read", Some(*start..=*end)),
+        GrowattMessage::WriteRegister { register, .. } => ("write", Some(*register..=*register)),
+        GrowattMessage::WriteRegisters { start, values } => {
+            let count = u16::try_from(values.len()).unwrap_or(u16::MAX).max(1);
+            ("write", Some(*start..=start.saturating_add(count - 1)))
+        }
+        GrowattMessage::ReadDatalogger { start, end } => ("datalogger-read", Some(*start..=*end)),
+        GrowattMessage::SetDatalogger { register, .. } => ("datalogger-write", Some(*register..=*register)),
+        GrowattMessage::TimeSync { .. } => (
+            "datalogger-write",
+            Some(DATALOGGER_TIME_REGISTER..=DATALOGGER_TIME_REGISTER),
+        ),
+        GrowattMessage::Unknown { .. } => ("unknown", None),
+        _ => return None,
+    })
+}
+
+// The rule name of a command from the message type in the frame header
+fn header_command_name(message_type: u8) -> &'static str {
+    match message_type {
+        MSG_READ_REGISTERS => "read",
+        MSG_WRITE_REGISTER | MSG_WRITE_REGISTERS => "write",
+        MSG_READ_DATALOGGER => "datalogger-read",
+        MSG_SET_DATALOGGER => "datalogger-write",
+        _ => "unknown",
+    }
+}
+
+impl FirewallPolicy {
+    pub fn check(&self, message: &GrowattMessage) -> Action {
+        let Some((name, registers)) = command_name(message) else {
+            return Action::Allow;
+        };
+
+        self.find(&[name], registers).map_or(Action::Allow, |rule| rule.action)
+    }
+
+    /// The action for a frame of the server that could not be decoded, e.g. a truncated payload or a bad crc. The
+    /// registers are not known so the `unknown` rules and the rules of the command in the header apply as if every
+    /// register was affected, without a matching rule the frame is dropped.
+    pub fn check_undecodable(&self, message_type: u8) -> Action {
+        self.find(&["unknown", header_command_name(message_type)], Some(0..=u16::MAX))
+            .map_or(Action::Drop, |rule| rule.action)
+    }
+
+    fn find(&self, names: &[&str], registers: Option<RangeInclusive<u16>>) -> Option<&Rule> {
+        let matches = |rule: &Rule| {
+            (rule.command == "*" || names.contains(&rule.command.as_str()))
+                && match (&rule.registers, &registers) {
+                    (None, _) => true,
+                    // allowing requires all the registers to be covered, blocking a single one is enough
+                    (Some(rule_registers), Some(registers)) if rule.action == Action::Allow => {
+                        rule_registers.contains(registers.start()) && rule_registers.contains(registers.end())
+                    }
+                    (Some(rule_registers), Some(registers)) => {
+                        rule_registers.start() <= registers.end() && registers.start() <= rule_registers.end()
+                    }
+                    (Some(_), None) => false,
+                }
+        };
+
+        self.rules.iter().find(|rule| matches(rule))
+    }
+}
+
+impl FromStr for FirewallPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let rule = |part: &str| -> Result<Rule, String> {
+            let (target, action) = part
+                .split_once('=')
+                .ok_or_else(|| format!("'{part}' must be <command>=<action>"))?;
+
+            let action = match action.trim() {
+                "allow" => Action::Allow,
+                "log" => Action::Log,
+                "drop" => Action::Drop,
+                other => return Err(format!("unknown action '{other}', expected allow, log or drop")),
+            };
+
+            let (command, registers) = match target.split_once(':') {
+                Some((command, registers)) => (command.trim(), Some(command::parse_registers(registers)?)),
+                None => (target.trim(), None),
+            };
+
+            if !["read", "write", "datalogger-read", "datalogger-write", "unknown", "*"].contains(&command) {
+                return Err(format!("unknown command '{command}'"));
+            }
+
+            Ok(Rule {
+                command: String::from(command),
+                registers,
+                action,
+            })
+        };
+
+        let rules = value
+            .split(',')
+            .filter(|part| !part.trim().is_empty())
+            .map(rule)
+            .collect::<Result<_, _>>()?;
+
+        Ok(FirewallPolicy { rules })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, FirewallPolicy};
+    use crate::{
+        framing::{GrowattFrame, MSG_READ_REGISTERS, MSG_WRITE_REGISTER},
+        message::{Direction, GrowattMessage},
+    };
+
+    #[test]
+    fn policy_rules() {
+        let policy: FirewallPolicy = "write:3=allow, write=drop,datalogger-write=drop,*=log".parse().unwrap();
+
+        let write = |register| GrowattMessage::WriteRegister { register, value: 1 };
+        assert_eq!(policy.check(&write(3)), Action::Allow);
+        assert_eq!(policy.check(&write(122)), Action::Drop);
+        assert_eq!(
+            policy.check(&GrowattMessage::WriteRegisters {
+                start: 3,
+                values: Vec::from([1])
+            }),
+            Action::Allow
+        );
+        assert_eq!(
+            policy.check(&GrowattMessage::WriteRegisters {
+                start: 2,
+                values: Vec::from([1, 2])
+            }),
+            Action::Drop
+        );
+        assert_eq!(
+            policy.check(&GrowattMessage::SetDatalogger {
+                register: 17,
+                value: Vec::new()
+            }),
+            Action::Drop
+        );
+        assert_eq!(
+            policy.check(&GrowattMessage::ReadRegisters { start: 0, end: 10 }),
+            Action::Log
+        );
+        assert_eq!(policy.check(&GrowattMessage::Ack { message_type: 4 }), Action::Allow);
+
+        // the registers of an undecodable frame are not known, it is only allowed by a rule for every register
+        let truncated_write = GrowattFrame::new(MSG_WRITE_REGISTER)
+            .serial("DLG0000001")
+            .payload(&[0, 3])
+            .build()
+            .unwrap();
+        assert!(GrowattMessage::decode(&truncated_write, Direction::FromServer).is_err());
+        assert_eq!(policy.check_undecodable(truncated_write[7]), Action::Drop);
+        assert_eq!(policy.check_undecodable(MSG_READ_REGISTERS), Action::Log);
+        let policy: FirewallPolicy = "write:3=allow,read=allow".parse().unwrap();
+        assert_eq!(policy.check_undecodable(MSG_WRITE_REGISTER), Action::Drop);
+        assert_eq!(policy.check_undecodable(MSG_READ_REGISTERS), Action::Allow);
+        let policy: FirewallPolicy = "unknown=log".parse().unwrap();
+        assert_eq!(policy.check_undecodable(MSG_WRITE_REGISTER), Action::Log);
+
+        assert!("write=block".parse::<FirewallPolicy>().is_err());
+        assert!("firmware=drop".parse::<FirewallPolicy>().is_err());
+        assert!("write:3-x=drop".parse::<FirewallPolicy>().is_err());
+    }
+}
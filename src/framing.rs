@@ -0,0 +1,282 @@
+use crc16::{State, MODBUS};
+
+use crate::{
+    dataprocessor::{GrowattData, HEADER_SIZE},
+    ProxyError,
+};
+
+pub const MSG_ANNOUNCE: u8 = 0x03;
+pub const MSG_DATA: u8 = 0x04;
+pub const MSG_READ_REGISTERS: u8 = 0x05;
+pub const MSG_WRITE_REGISTER: u8 = 0x06;
+pub const MSG_WRITE_REGISTERS: u8 = 0x10;
+pub const MSG_PING: u8 = 0x16;
+pub const MSG_SET_DATALOGGER: u8 = 0x18;
+pub const MSG_READ_DATALOGGER: u8 = 0x19;
+pub const MSG_BUFFERED_SMART_METER: u8 = 0x1b;
+pub const MSG_SMART_METER: u8 = 0x20;
+pub const MSG_BUFFERED_DATA: u8 = 0x50;
+
+// Upper bound for the payload length in a frame header, larger values mean we lost track of the stream
+const MAX_PAYLOAD_SIZE: usize = 4096;
+pub const SERIAL_LENGTH: usize = 10;
+
+/// Buffers the raw byte stream of a Growatt TCP connection and cuts it into complete frames
+/// using the payload length field of the header (bytes 4..6)
+#[derive(Default)]
+pub struct FrameBuffer {
+    data: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new() -> FrameBuffer {
+        FrameBuffer::default()
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    /// Number of buffered bytes that are not yet part of a complete frame
+    pub fn pending(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns the next complete frame (header included) or None when more data is needed
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        self.resync();
+
+        if self.data.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let frame_size = HEADER_SIZE + FrameBuffer::payload_length(&self.data);
+        if self.data.len() < frame_size {
+            return None;
+        }
+
+        Some(self.data.drain(..frame_size).collect())
+    }
+
+    fn payload_length(data: &[u8]) -> usize {
+        u16::from_be_bytes([data[4], data[5]]) as usize
+    }
+
+    fn is_valid_header(data: &[u8]) -> bool {
+        // the protocol id is a small number stored big endian in bytes 2..4
+        data[2] == 0 && (1..=0x10).contains(&data[3]) && FrameBuffer::payload_length(data) <= MAX_PAYLOAD_SIZE
+    }
+
+    // Skip bytes until the buffer starts with something that looks like a header
+    fn resync(&mut self) {
+        let mut skip = 0;
+        while self.data.len() - skip >= HEADER_SIZE && !FrameBuffer::is_valid_header(&self.data[skip..]) {
+            skip += 1;
+        }
+
+        if skip > 0 {
+            log::warn!("Discarding {skip} bytes of unframed data");
+            self.data.drain(..skip);
+        }
+    }
+}
+
+/// Protocol versions 5 and 6 terminate every frame with a modbus crc of the preceding bytes
+pub fn has_crc(frame: &[u8]) -> bool {
+    protocol_has_crc(frame[3])
+}
+
+fn protocol_has_crc(protocol: u8) -> bool {
+    protocol == 0x05 || protocol == 0x06
+}
+
+/// Protocol 6 pads the datalogger serial at the start of the payload to 30 bytes
+pub fn serial_block_length(protocol: u8) -> usize {
+    if protocol == 0x06 {
+        3 * SERIAL_LENGTH
+    } else {
+        SERIAL_LENGTH
+    }
+}
+
+/// Appends the crc to a frame that does not have one yet
+pub fn append_crc(frame: &mut Vec<u8>) {
+    let crc = State::<MODBUS>::calculate(frame);
+    frame.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Recalculates the trailing crc after the frame was modified
+pub fn update_crc(frame: &mut [u8]) {
+    let size = frame.len();
+    let crc = State::<MODBUS>::calculate(&frame[..size - 2]);
+    frame[size - 2..].copy_from_slice(&crc.to_be_bytes());
+}
+
+/// Builds a frame the way the inverter and the server send them: the header with the payload length, the masked
+/// payload and the crc for the protocols that have one
+#[derive(Clone, Debug)]
+pub struct GrowattFrame {
+    packet_index: u16,
+    protocol: u8,
+    device: u8,
+    message_type: u8,
+    serial: Option<String>,
+    payload: Vec<u8>,
+}
+
+impl GrowattFrame {
+    /// A protocol 6 frame of the given message type
+    pub fn new(message_type: u8) -> GrowattFrame {
+        GrowattFrame {
+            packet_index: 1,
+            protocol: 0x06,
+            device: 0x01,
+            message_type,
+            serial: None,
+            payload: Vec::new(),
+        }
+    }
+
+    /// A frame with the packet index, protocol, device and message type of the given header
+    pub fn reply_to(header: &[u8]) -> GrowattFrame {
+        GrowattFrame {
+            packet_index: u16::from_be_bytes([header[0], header[1]]),
+            protocol: header[3],
+            device: header[6],
+            message_type: header[7],
+            serial: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn packet_index(mut self, packet_index: u16) -> GrowattFrame {
+        self.packet_index = packet_index;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: u8) -> GrowattFrame {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn device(mut self, device: u8) -> GrowattFrame {
+        self.device = device;
+        self
+    }
+
+    /// The datalogger serial that starts the payload, see `serial_block_length`
+    pub fn serial(mut self, serial: &str) -> GrowattFrame {
+        self.serial = Some(String::from(serial));
+        self
+    }
+
+    /// The unmasked payload, following the serial when one is set
+    pub fn payload(mut self, payload: &[u8]) -> GrowattFrame {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>, ProxyError> {
+        let has_crc = protocol_has_crc(self.protocol);
+
+        let mut body = Vec::new();
+        if let Some(serial) = &self.serial {
+            if serial.len() > SERIAL_LENGTH {
+                return Err(ProxyError::RuntimeError(format!("Serial too long: {serial}")));
+            }
+
+            body.extend_from_slice(serial.as_bytes());
+            body.resize(serial_block_length(self.protocol), 0);
+        }
+        body.extend_from_slice(&self.payload);
+
+        let payload_length = body.len() + if has_crc { 2 } else { 0 };
+        if payload_length > MAX_PAYLOAD_SIZE {
+            return Err(ProxyError::RuntimeError(format!(
+                "Payload too large: {payload_length} bytes"
+            )));
+        }
+
+        let mut frame = Vec::with_capacity(HEADER_SIZE + payload_length);
+        frame.extend_from_slice(&self.packet_index.to_be_bytes());
+        frame.extend_from_slice(&[0, self.protocol]);
+        frame.extend_from_slice(&(payload_length as u16).to_be_bytes());
+        frame.extend_from_slice(&[self.device, self.message_type]);
+        frame.extend_from_slice(&body);
+
+        if has_crc {
+            GrowattData::encrypt(&mut frame);
+            append_crc(&mut frame);
+        }
+
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameBuffer, GrowattFrame, MSG_DATA};
+    use crate::dataprocessor::GrowattData;
+
+    #[test]
+    fn split_and_merged_frames() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+
+        let mut stream = growatt_data.to_vec();
+        stream.extend_from_slice(growatt_data);
+
+        let mut frames = FrameBuffer::new();
+        frames.extend(&stream[..100]);
+        assert!(frames.next_frame().is_none());
+
+        frames.extend(&stream[100..700]);
+        assert_eq!(frames.next_frame().unwrap(), growatt_data.to_vec());
+        assert!(frames.next_frame().is_none());
+
+        frames.extend(&stream[700..]);
+        assert_eq!(frames.next_frame().unwrap(), growatt_data.to_vec());
+        assert!(frames.next_frame().is_none());
+        assert_eq!(frames.pending(), 0);
+    }
+
+    #[test]
+    fn skip_garbage() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+
+        let mut frames = FrameBuffer::new();
+        frames.extend(&[0xff, 0xff, 0xff]);
+        frames.extend(growatt_data);
+        assert_eq!(frames.next_frame().unwrap(), growatt_data.to_vec());
+    }
+
+    #[test]
+    fn build_frame() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        let mut data = growatt_data.to_vec();
+        GrowattData::decrypt_data(&mut data);
+
+        // rebuilding the announce from its header and unmasked payload gives the captured frame
+        let frame = GrowattFrame::reply_to(growatt_data)
+            .payload(&data[8..data.len() - 2])
+            .build()
+            .unwrap();
+        assert_eq!(frame, growatt_data.to_vec());
+
+        let frame = GrowattFrame::new(MSG_DATA)
+            .packet_index(7)
+            .serial("DLG0000001")
+            .payload(b"MFK0CE306Q")
+            .build()
+            .unwrap();
+        assert_eq!(frame.len(), 8 + 30 + 10 + 2);
+        assert_eq!(frame[..8], [0, 7, 0, 6, 0, 42, 1, MSG_DATA]);
+        assert!(GrowattData::validate_integity(&frame).is_ok());
+
+        let mut data = frame.clone();
+        GrowattData::decrypt_data(&mut data);
+        assert_eq!(&data[8..18], b"DLG0000001");
+        assert_eq!(&data[38..48], b"MFK0CE306Q");
+
+        assert!(GrowattFrame::new(MSG_DATA).serial("DLG00000001").build().is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use serde_json::{json, Map, Value};
+
+use crate::{
+    dataprocessor::{FieldValue, GrowattData},
+    mqtt::MqttMessage,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SensorClass {
+    pub device_class: Option<&'static str>,
+    pub unit: Option<&'static str>,
+    pub state_class: Option<&'static str>,
+}
+
+const fn sensor(device_class: &'static str, unit: &'static str, state_class: &'static str) -> SensorClass {
+    SensorClass {
+        device_class: Some(device_class),
+        unit: Some(unit),
+        state_class: Some(state_class),
+    }
+}
+
+const ENERGY: SensorClass = sensor("energy", "kWh", "total_increasing");
+const POWER: SensorClass = sensor("power", "W", "measurement");
+const VOLTAGE: SensorClass = sensor("voltage", "V", "measurement");
+const CURRENT: SensorClass = sensor("current", "A", "measurement");
+const FREQUENCY: SensorClass = sensor("frequency", "Hz", "measurement");
+const TEMPERATURE: SensorClass = sensor("temperature", "°C", "measurement");
+const DURATION: SensorClass = sensor("duration", "h", "total_increasing");
+const BATTERY: SensorClass = sensor("battery", "%", "measurement");
+const APPARENT_POWER: SensorClass = sensor("apparent_power", "VA", "measurement");
+const REACTIVE_POWER: SensorClass = sensor("reactive_power", "var", "measurement");
+const POWER_FACTOR: SensorClass = SensorClass {
+    device_class: Some("power_factor"),
+    unit: None,
+    state_class: Some("measurement"),
+};
+const REACTIVE_ENERGY: SensorClass = SensorClass {
+    device_class: None,
+    unit: Some("kvarh"),
+    state_class: Some("total_increasing"),
+};
+
+// Matched in order against the field names, the first pattern contained in the name wins
+const SENSOR_CLASSES: &[(&str, SensorClass)] = &[
+    ("reactiveenergy", REACTIVE_ENERGY),
+    ("energy", ENERGY),
+    ("epv", ENERGY),
+    ("etouser", ENERGY),
+    ("etogrid", ENERGY),
+    ("edischarge", ENERGY),
+    ("echarge", ENERGY),
+    ("elocalload", ENERGY),
+    ("worktime", DURATION),
+    ("voltage", VOLTAGE),
+    ("volt", VOLTAGE),
+    ("current", CURRENT),
+    ("vbat", VOLTAGE),
+    ("apparentpower", APPARENT_POWER),
+    ("reactivepower", REACTIVE_POWER),
+    ("powerfactor", POWER_FACTOR),
+    ("watt", POWER),
+    ("power", POWER),
+    ("pactouser", POWER),
+    ("pactogrid", POWER),
+    ("plocalload", POWER),
+    ("pdischarge", POWER),
+    ("pcharge", POWER),
+    ("soc", BATTERY),
+    ("frequentie", FREQUENCY),
+    ("frequency", FREQUENCY),
+    ("temperature", TEMPERATURE),
+];
+
+/// Home Assistant classification of a parsed field
+pub fn sensor_class(field: &str, value: &FieldValue) -> SensorClass {
+    let none = SensorClass {
+        device_class: None,
+        unit: None,
+        state_class: None,
+    };
+
+    match value {
+        FieldValue::Text(_) => none,
+        FieldValue::Date(_) => SensorClass {
+            device_class: Some("timestamp"),
+            ..none
+        },
+        FieldValue::Number(_) => SENSOR_CLASSES
+            .iter()
+            .find(|(pattern, _)| field.contains(pattern))
+            .map(|(_, class)| *class)
+            .unwrap_or(SensorClass {
+                state_class: Some("measurement"),
+                ..none
+            }),
+    }
+}
+
+/// Retained discovery configurations for every field of the data, all fields of an inverter are grouped
+/// in a single device. The sensors read their value from the json object published on the state topic and
+/// are available when all the availability topics report online.
+pub fn discovery_messages(
+    data: &GrowattData,
+    discovery_prefix: &str,
+    state_topic: &str,
+    availability_topics: &[String],
+) -> Vec<MqttMessage> {
+    let (Some(serial), Some(device_id)) = (data.serial(), data.device_id()) else {
+        return Vec::new();
+    };
+
+    let node_id = format!("growatt_{device_id}");
+    let device = if data.is_smart_meter() {
+        json!({
+            "identifiers": [node_id],
+            "name": format!("Growatt {serial} meter"),
+            "manufacturer": "Growatt",
+            "model": "Smart meter",
+            "via_device": format!("growatt_{serial}"),
+        })
+    } else {
+        json!({
+            "identifiers": [node_id],
+            "name": format!("Growatt {serial}"),
+            "manufacturer": "Growatt",
+            "model": data.layout(),
+        })
+    };
+
+    let availability: Vec<Value> = availability_topics
+        .iter()
+        .map(|topic| json!({ "topic": topic }))
+        .collect();
+
+    data.fields
+        .iter()
+        .map(|field| {
+            let class = sensor_class(&field.name, &field.value);
+
+            let mut config = Map::new();
+            config.insert(String::from("name"), Value::from(field.name.as_str()));
+            config.insert(
+                String::from("unique_id"),
+                Value::from(format!("{node_id}_{}", field.name)),
+            );
+            config.insert(String::from("state_topic"), Value::from(state_topic));
+            config.insert(
+                String::from("value_template"),
+                Value::from(format!("{{{{ value_json.{} }}}}", field.name)),
+            );
+
+            if let Some(device_class) = class.device_class {
+                config.insert(String::from("device_class"), Value::from(device_class));
+            }
+
+            if let Some(unit) = class.unit {
+                config.insert(String::from("unit_of_measurement"), Value::from(unit));
+            }
+
+            if let Some(state_class) = class.state_class {
+                config.insert(String::from("state_class"), Value::from(state_class));
+            }
+
+            config.insert(String::from("availability"), Value::from(availability.clone()));
+            config.insert(String::from("availability_mode"), Value::from("all"));
+            config.insert(String::from("device"), device.clone());
+
+            MqttMessage {
+                topic: format!("{discovery_prefix}/sensor/{node_id}/{}/config", field.name),
+                payload: Value::Object(config).to_string(),
+                retain: true,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use num_rational::Rational64;
+
+    use super::{sensor_class, ENERGY, POWER, REACTIVE_ENERGY, REACTIVE_POWER, VOLTAGE};
+    use crate::dataprocessor::FieldValue;
+
+    #[test]
+    fn field_classes() {
+        let number = FieldValue::Number(Rational64::from_integer(1));
+
+        assert_eq!(sensor_class("pvenergytoday", &number), ENERGY);
+        assert_eq!(sensor_class("epv1total", &number), ENERGY);
+        assert_eq!(sensor_class("pvpowerout", &number), POWER);
+        assert_eq!(sensor_class("pv1watt", &number), POWER);
+        assert_eq!(sensor_class("pvgridvoltage", &number), VOLTAGE);
+        assert_eq!(sensor_class("pbusvolt", &number), VOLTAGE);
+        assert_eq!(sensor_class("pvstatus", &number).unit, None);
+        assert_eq!(sensor_class("reactivepowerl1", &number), REACTIVE_POWER);
+        assert_eq!(sensor_class("importreactiveenergy", &number), REACTIVE_ENERGY);
+        assert_eq!(sensor_class("exportenergy", &number), ENERGY);
+    }
+}
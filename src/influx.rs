@@ -0,0 +1,438 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{Read, Write},
+    net::TcpStream,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+
+use crate::{
+    dataprocessor::{FieldValue, GrowattData},
+    sink::DataSink,
+    ProxyError,
+};
+
+const CHANNEL_SIZE: usize = 100;
+// points kept in memory while the database is unreachable, the oldest points are dropped first
+const MAX_PENDING: usize = 10000;
+const BATCH_SIZE: usize = 500;
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub enum InfluxTarget {
+    /// InfluxDB 1.x: /write?db=
+    V1 {
+        database: String,
+        credentials: Option<(String, String)>,
+    },
+    /// InfluxDB 2.x: /api/v2/write?org=&bucket=
+    V2 {
+        org: String,
+        bucket: String,
+        token: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct InfluxConfig {
+    /// host:port of the http api
+    pub address: String,
+    pub target: InfluxTarget,
+    pub measurement: String,
+    /// Delay before a failed write is retried
+    pub retry_interval: Duration,
+}
+
+/// The InfluxDB command line options shared by the binaries
+#[derive(clap::Args, Debug)]
+pub struct InfluxArgs {
+    // influxdb http api, e.g. http://localhost:8086 (https is not supported)
+    #[clap(long = "influx-url", env = "GP_INFLUX_URL")]
+    pub influx_url: Option<String>,
+
+    // database for the 1.x write api
+    #[clap(long = "influx-db", env = "GP_INFLUX_DB", conflicts_with = "influx_bucket")]
+    pub influx_db: Option<String>,
+
+    #[clap(long = "influx-username", env = "GP_INFLUX_USERNAME")]
+    pub influx_username: Option<String>,
+
+    #[clap(long = "influx-password", env = "GP_INFLUX_PASSWORD")]
+    pub influx_password: Option<String>,
+
+    // bucket for the 2.x write api, requires the organization
+    #[clap(long = "influx-bucket", env = "GP_INFLUX_BUCKET", requires = "influx_org")]
+    pub influx_bucket: Option<String>,
+
+    #[clap(long = "influx-org", env = "GP_INFLUX_ORG")]
+    pub influx_org: Option<String>,
+
+    #[clap(long = "influx-token", env = "GP_INFLUX_TOKEN")]
+    pub influx_token: Option<String>,
+
+    #[clap(
+        long = "influx-measurement",
+        env = "GP_INFLUX_MEASUREMENT",
+        default_value = "growatt"
+    )]
+    pub influx_measurement: String,
+
+    // seconds between retries of failed writes
+    #[clap(
+        long = "influx-retry-interval",
+        env = "GP_INFLUX_RETRY_INTERVAL",
+        default_value_t = 30
+    )]
+    pub influx_retry_interval: u64,
+}
+
+impl InfluxArgs {
+    pub fn config(&self) -> Result<Option<InfluxConfig>, ProxyError> {
+        let Some(url) = &self.influx_url else {
+            return Ok(None);
+        };
+
+        let address = match url.strip_prefix("http://") {
+            Some(address) => address.trim_end_matches('/'),
+            None if url.starts_with("https://") => {
+                return Err(ProxyError::RuntimeError(String::from(
+                    "InfluxDB over https is not supported",
+                )))
+            }
+            None => url.trim_end_matches('/'),
+        };
+
+        let target = match (&self.influx_db, &self.influx_bucket, &self.influx_org) {
+            (Some(database), None, _) => InfluxTarget::V1 {
+                database: database.clone(),
+                credentials: self
+                    .influx_username
+                    .as_ref()
+                    .map(|user| (user.clone(), self.influx_password.clone().unwrap_or_default())),
+            },
+            (None, Some(bucket), Some(org)) => InfluxTarget::V2 {
+                org: org.clone(),
+                bucket: bucket.clone(),
+                token: self.influx_token.clone(),
+            },
+            _ => {
+                return Err(ProxyError::RuntimeError(String::from(
+                    "InfluxDB requires a database (1.x) or an organization and bucket (2.x)",
+                )))
+            }
+        };
+
+        Ok(Some(InfluxConfig {
+            address: String::from(address),
+            target,
+            measurement: self.influx_measurement.clone(),
+            retry_interval: Duration::from_secs(self.influx_retry_interval),
+        }))
+    }
+}
+
+/// Writes the inverter data to InfluxDB from a background thread, points are batched and kept until the
+/// database accepts them.
+#[derive(Clone)]
+pub struct InfluxWriter {
+    sender: SyncSender<String>,
+    measurement: String,
+}
+
+impl InfluxWriter {
+    pub fn new(cfg: &InfluxConfig) -> Result<InfluxWriter, ProxyError> {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_SIZE);
+
+        let thread_cfg = cfg.clone();
+        std::thread::Builder::new()
+            .name(String::from("influx"))
+            .spawn(move || run_writer(thread_cfg, receiver))?;
+
+        Ok(InfluxWriter {
+            sender,
+            measurement: cfg.measurement.clone(),
+        })
+    }
+
+    /// Queues the data for writing, does not block
+    pub fn write(&self, data: &GrowattData) -> Result<(), ProxyError> {
+        let Some(line) = line_protocol(&self.measurement, data) else {
+            return Ok(());
+        };
+
+        self.sender
+            .try_send(line)
+            .map_err(|err| ProxyError::RuntimeError(format!("InfluxDB queue: {err}")))
+    }
+}
+
+impl DataSink for InfluxWriter {
+    fn name(&self) -> &str {
+        "influxdb"
+    }
+
+    // the points carry the time of the packet, the history lands at its own time
+    fn publishes_buffered(&self) -> bool {
+        true
+    }
+
+    fn publish(&self, data: &GrowattData) -> Result<(), ProxyError> {
+        self.write(data)
+    }
+}
+
+/// A single line protocol point: the serial and layout as tags, all the numeric fields as float fields,
+/// timestamped (in seconds) with the date of the packet
+pub fn line_protocol(measurement: &str, data: &GrowattData) -> Option<String> {
+    let mut fields = String::new();
+    let mut timestamp = None;
+
+    for field in &data.fields {
+        match &field.value {
+            FieldValue::Number(num) => {
+                let value = *num.numer() as f64 / *num.denom() as f64;
+                if !fields.is_empty() {
+                    fields.push(',');
+                }
+                let _ = write!(fields, "{}={value:?}", escape(&field.name));
+            }
+            FieldValue::Date(date) if field.name == "date" => timestamp = Some(date.timestamp()),
+            _ => {}
+        }
+    }
+
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut line = escape(measurement);
+    if let Some(serial) = data.serial() {
+        let _ = write!(line, ",serial={}", escape(&serial));
+    }
+    let _ = write!(
+        line,
+        ",layout={} {fields} {}",
+        escape(&data.layout()),
+        timestamp.unwrap_or_else(|| Utc::now().timestamp())
+    );
+
+    Some(line)
+}
+
+// escaping of measurements, tag keys, tag values and field keys
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{b:02X}");
+        }
+    }
+
+    encoded
+}
+
+fn request_path(target: &InfluxTarget) -> String {
+    match target {
+        InfluxTarget::V1 { database, credentials } => {
+            let mut path = format!("/write?db={}&precision=s", url_encode(database));
+            if let Some((user, password)) = credentials {
+                let _ = write!(path, "&u={}&p={}", url_encode(user), url_encode(password));
+            }
+            path
+        }
+        InfluxTarget::V2 { org, bucket, .. } => format!(
+            "/api/v2/write?org={}&bucket={}&precision=s",
+            url_encode(org),
+            url_encode(bucket)
+        ),
+    }
+}
+
+enum WriteError {
+    // the database can not be reached or is unavailable, the write can be retried
+    Retry(String),
+    // the points were rejected, retrying will not help
+    Rejected(String),
+}
+
+fn post(cfg: &InfluxConfig, body: &str) -> Result<(), WriteError> {
+    let retry = |err: std::io::Error| WriteError::Retry(err.to_string());
+
+    let mut stream = TcpStream::connect(&cfg.address).map_err(retry)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT)).map_err(retry)?;
+    stream.set_write_timeout(Some(IO_TIMEOUT)).map_err(retry)?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+        request_path(&cfg.target),
+        cfg.address,
+        body.len()
+    );
+    if let InfluxTarget::V2 { token: Some(token), .. } = &cfg.target {
+        let _ = write!(request, "Authorization: Token {token}\r\n");
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    stream.write_all(request.as_bytes()).map_err(retry)?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).map_err(retry)?;
+    let response = String::from_utf8_lossy(&response);
+
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| WriteError::Retry(String::from("Invalid http response")))?;
+
+    match status {
+        200..=299 => Ok(()),
+        429 | 500..=599 => Err(WriteError::Retry(format!("http status {status}"))),
+        _ => {
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+            Err(WriteError::Rejected(format!("http status {status}: {}", body.trim())))
+        }
+    }
+}
+
+fn run_writer(cfg: InfluxConfig, receiver: Receiver<String>) {
+    let mut pending = VecDeque::new();
+    let mut retry_at: Option<Instant> = None;
+
+    loop {
+        // wait for new points, or until the next retry when there are points waiting
+        let received = match retry_at {
+            Some(at) => receiver.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok(line) => pending.push_back(line),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        while let Ok(line) = receiver.try_recv() {
+            pending.push_back(line);
+        }
+
+        if pending.len() > MAX_PENDING {
+            log::warn!("InfluxDB unreachable, dropping {} points", pending.len() - MAX_PENDING);
+            pending.drain(..pending.len() - MAX_PENDING);
+        }
+
+        if retry_at.is_some_and(|at| Instant::now() < at) {
+            continue;
+        }
+
+        retry_at = None;
+        while !pending.is_empty() {
+            let batch: Vec<&str> = pending.iter().take(BATCH_SIZE).map(String::as_str).collect();
+            let count = batch.len();
+
+            match post(&cfg, &batch.join("\n")) {
+                Ok(()) => log::debug!("Wrote {count} points to InfluxDB"),
+                Err(WriteError::Rejected(err)) => log::warn!("InfluxDB rejected {count} points: {err}"),
+                Err(WriteError::Retry(err)) => {
+                    log::warn!(
+                        "InfluxDB write failed, retrying in {}s: {err}",
+                        cfg.retry_interval.as_secs()
+                    );
+                    retry_at = Some(Instant::now() + cfg.retry_interval);
+                    break;
+                }
+            }
+
+            pending.drain(..count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        time::Duration,
+    };
+
+    use super::{line_protocol, InfluxConfig, InfluxTarget, InfluxWriter};
+    use crate::{dataprocessor::GrowattData, layouts};
+
+    // reads a single http request and answers it with the given status, returns the request
+    fn serve_request(listener: &TcpListener, status: &str) -> String {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let n = socket.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..n]);
+
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if body.len() >= length {
+                    socket
+                        .write_all(format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").as_bytes())
+                        .unwrap();
+                    return text;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn write_and_retry() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        let mut data = growatt_data.to_vec();
+        let gd = GrowattData::from_buffer(&mut data, &layouts::t065004x()).unwrap();
+
+        let line = line_protocol("growatt", &gd).unwrap();
+        assert!(line.starts_with("growatt,serial=MFK0CE306Q,layout=T065103 pvstatus=1.0,"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let writer = InfluxWriter::new(&InfluxConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            target: InfluxTarget::V2 {
+                org: String::from("home"),
+                bucket: String::from("solar"),
+                token: Some(String::from("secret")),
+            },
+            measurement: String::from("growatt"),
+            retry_interval: Duration::from_millis(10),
+        })
+        .unwrap();
+
+        writer.write(&gd).unwrap();
+
+        let failed = serve_request(&listener, "503 Service Unavailable");
+        let retried = serve_request(&listener, "204 No Content");
+        assert_eq!(failed, retried);
+        assert!(retried.starts_with("POST /api/v2/write?org=home&bucket=solar&precision=s HTTP/1.1\r\n"));
+        assert!(retried.contains("Authorization: Token secret\r\n"));
+        assert!(retried.ends_with(&line));
+    }
+}
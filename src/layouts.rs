@@ -1,4 +1,12 @@
-use crate::dataprocessor::{FieldSpecification, LayoutSpecification};
+use std::{collections::HashSet, path::Path, sync::OnceLock};
+
+use serde_json::Value;
+
+use crate::{
+    dataprocessor::{FieldSpecification, LayoutSpecification},
+    framing::{MSG_BUFFERED_SMART_METER, MSG_SMART_METER},
+    ProxyError,
+};
 
 pub fn t065004x() -> LayoutSpecification {
     LayoutSpecification::new(
@@ -65,21 +73,483 @@ pub fn t06nnnnx(offset: usize) -> LayoutSpecification {
             FieldSpecification::number("pvenergytotal", 362, 4, 10),
             FieldSpecification::number("pvtemperature", 530, 2, 10),
             FieldSpecification::number("pvipmtemperature", 534, 2, 10),
+            FieldSpecification::number("pvfaultcode", 578, 2, 1),
+            FieldSpecification::number("pvwarningcode", 598, 2, 1),
         ]),
         offset,
     )
 }
 
-pub fn detect_layout(header: &[u8; 8]) -> LayoutSpecification {
-    let mut layout = format!("T{:02x}{:02x}{:02x}", header[3], header[6], header[7]);
-    let is_smart_meter = header[7] == 0x20 || header[7] == 0x1b;
+// The storage registers (1000 and up) of the SPH and SPA inverters, the register block follows the inverter
+// registers in the data record: register 1009 (discharge power) is at offset 1258.
+fn storage_fields() -> Vec<FieldSpecification> {
+    Vec::from([
+        FieldSpecification::number("pdischarge1", 1258, 4, 10),
+        FieldSpecification::number("pcharge1", 1266, 4, 10),
+        FieldSpecification::number("vbat", 1274, 2, 10),
+        FieldSpecification::number("soc", 1278, 2, 1),
+        FieldSpecification::number("pactouser", 1282, 4, 10),
+        FieldSpecification::number("pactousertotal", 1306, 4, 10),
+        FieldSpecification::number("pactogrid", 1314, 4, 10),
+        FieldSpecification::number("pactogridtotal", 1338, 4, 10),
+        FieldSpecification::number("plocalload", 1346, 4, 10),
+        FieldSpecification::number("plocalloadtotal", 1370, 4, 10),
+        FieldSpecification::signed_number("batterytemperature", 1382, 2, 10),
+        FieldSpecification::number("etousertoday", 1398, 4, 10),
+        FieldSpecification::number("etousertotal", 1406, 4, 10),
+        FieldSpecification::number("etogridtoday", 1414, 4, 10),
+        FieldSpecification::number("etogridtotal", 1422, 4, 10),
+        FieldSpecification::number("edischarge1today", 1430, 4, 10),
+        FieldSpecification::number("edischarge1total", 1438, 4, 10),
+        FieldSpecification::number("echarge1today", 1446, 4, 10),
+        FieldSpecification::number("echarge1total", 1454, 4, 10),
+        FieldSpecification::number("elocalloadtoday", 1462, 4, 10),
+        FieldSpecification::number("elocalloadtotal", 1470, 4, 10),
+    ])
+}
+
+/// SPH hybrid inverter: PV inputs and battery storage
+pub fn t06nnnnxsph() -> LayoutSpecification {
+    let mut fields = Vec::from([
+        FieldSpecification::text("pvserial", 76, 10),
+        FieldSpecification::date("date", 136),
+        FieldSpecification::number("pvstatus", 158, 2, 1),
+        FieldSpecification::number("pvpowerin", 162, 4, 10),
+        FieldSpecification::number("pv1voltage", 170, 2, 10),
+        FieldSpecification::number("pv1current", 174, 2, 10),
+        FieldSpecification::number("pv1watt", 178, 4, 10),
+        FieldSpecification::number("pv2voltage", 186, 2, 10),
+        FieldSpecification::number("pv2current", 190, 2, 10),
+        FieldSpecification::number("pv2watt", 194, 4, 10),
+        FieldSpecification::signed_number("pvpowerout", 298, 4, 10),
+        FieldSpecification::number("pvfrequentie", 306, 2, 100),
+        FieldSpecification::number("pvgridvoltage", 310, 2, 10),
+        FieldSpecification::number("pvgridcurrent", 314, 2, 10),
+        FieldSpecification::number("pvgridpower", 318, 4, 10),
+        FieldSpecification::number("pvenergytoday", 370, 4, 10),
+        FieldSpecification::number("pvenergytotal", 378, 4, 10),
+        FieldSpecification::number("totworktime", 386, 4, 7200),
+        FieldSpecification::number("epv1today", 394, 4, 10),
+        FieldSpecification::number("epv1total", 402, 4, 10),
+        FieldSpecification::number("epv2today", 410, 4, 10),
+        FieldSpecification::number("epv2total", 418, 4, 10),
+        FieldSpecification::number("epvtotal", 522, 4, 10),
+        FieldSpecification::signed_number("pvtemperature", 530, 2, 10),
+        FieldSpecification::signed_number("pvipmtemperature", 534, 2, 10),
+        FieldSpecification::signed_number("pvboosttemperature", 538, 2, 10),
+        FieldSpecification::number("pbusvolt", 550, 2, 1),
+        FieldSpecification::number("nbusvolt", 554, 2, 1),
+        FieldSpecification::number("pvfaultcode", 578, 2, 1),
+        FieldSpecification::number("pvwarningcode", 598, 2, 1),
+    ]);
+    fields.extend(storage_fields());
+
+    LayoutSpecification::new("T06NNNNXSPH", true, fields)
+}
+
+/// SPA AC coupled storage inverter: no PV inputs, the inverter registers describe the AC side
+pub fn t06nnnnxspa() -> LayoutSpecification {
+    let mut fields = Vec::from([
+        FieldSpecification::text("pvserial", 76, 10),
+        FieldSpecification::date("date", 136),
+        FieldSpecification::number("pvstatus", 158, 2, 1),
+        FieldSpecification::signed_number("pvpowerout", 298, 4, 10),
+        FieldSpecification::number("pvfrequentie", 306, 2, 100),
+        FieldSpecification::number("pvgridvoltage", 310, 2, 10),
+        FieldSpecification::number("pvgridcurrent", 314, 2, 10),
+        FieldSpecification::number("pvgridpower", 318, 4, 10),
+        FieldSpecification::number("pvenergytoday", 370, 4, 10),
+        FieldSpecification::number("pvenergytotal", 378, 4, 10),
+        FieldSpecification::number("totworktime", 386, 4, 7200),
+        FieldSpecification::signed_number("pvtemperature", 530, 2, 10),
+        FieldSpecification::signed_number("pvipmtemperature", 534, 2, 10),
+        FieldSpecification::number("pvfaultcode", 578, 2, 1),
+        FieldSpecification::number("pvwarningcode", 598, 2, 1),
+    ]);
+    fields.extend(storage_fields());
+
+    LayoutSpecification::new("T06NNNNXSPA", true, fields)
+}
+
+/// Smart meter attached to the inverter, the record carries the serial of the inverter. The meter values are
+/// scaled 32 bit numbers.
+pub fn t06nnnnxsmt() -> LayoutSpecification {
+    LayoutSpecification::new(
+        "T06NNNNXSMT",
+        true,
+        Vec::from([
+            FieldSpecification::text("pvserial", 76, 10),
+            FieldSpecification::signed_number("voltagel1", 118, 4, 10),
+            FieldSpecification::signed_number("voltagel2", 126, 4, 10),
+            FieldSpecification::signed_number("voltagel3", 134, 4, 10),
+            FieldSpecification::signed_number("currentl1", 142, 4, 10),
+            FieldSpecification::signed_number("currentl2", 150, 4, 10),
+            FieldSpecification::signed_number("currentl3", 158, 4, 10),
+            FieldSpecification::signed_number("activepowerl1", 166, 4, 10),
+            FieldSpecification::signed_number("activepowerl2", 174, 4, 10),
+            FieldSpecification::signed_number("activepowerl3", 182, 4, 10),
+            FieldSpecification::signed_number("apparentpowerl1", 190, 4, 10),
+            FieldSpecification::signed_number("apparentpowerl2", 198, 4, 10),
+            FieldSpecification::signed_number("apparentpowerl3", 206, 4, 10),
+            FieldSpecification::signed_number("reactivepowerl1", 214, 4, 10),
+            FieldSpecification::signed_number("reactivepowerl2", 222, 4, 10),
+            FieldSpecification::signed_number("reactivepowerl3", 230, 4, 10),
+            FieldSpecification::signed_number("powerfactorl1", 238, 4, 1000),
+            FieldSpecification::signed_number("powerfactorl2", 246, 4, 1000),
+            FieldSpecification::signed_number("powerfactorl3", 254, 4, 1000),
+            FieldSpecification::signed_number("activepower", 262, 4, 10),
+            FieldSpecification::signed_number("apparentpower", 286, 4, 10),
+            FieldSpecification::signed_number("reactivepower", 294, 4, 10),
+            FieldSpecification::signed_number("powerfactor", 302, 4, 1000),
+            FieldSpecification::signed_number("frequency", 310, 4, 10),
+            FieldSpecification::signed_number("voltagel12", 318, 4, 10),
+            FieldSpecification::signed_number("voltagel23", 326, 4, 10),
+            FieldSpecification::signed_number("voltagel31", 334, 4, 10),
+            FieldSpecification::signed_number("importenergy", 342, 4, 10),
+            FieldSpecification::signed_number("exportenergy", 350, 4, 10),
+            FieldSpecification::signed_number("importreactiveenergy", 358, 4, 10),
+            FieldSpecification::signed_number("exportreactiveenergy", 366, 4, 10),
+        ]),
+    )
+}
+
+/// The inverter family, the data records of the storage inverters can not be told apart by their header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum InverterType {
+    #[default]
+    Default,
+    Sph,
+    Spa,
+}
+
+static INVERTER_TYPE: OnceLock<InverterType> = OnceLock::new();
 
-    if is_smart_meter {
+/// Selects the built-in layouts for the inverter type, can only be set once
+pub fn set_inverter_type(inverter_type: InverterType) -> Result<(), ProxyError> {
+    INVERTER_TYPE
+        .set(inverter_type)
+        .map_err(|_| ProxyError::RuntimeError(String::from("Inverter type already set")))
+}
+
+/// The layout name of a frame: protocol, device and message type, smart meter records get an 'X' suffix
+pub fn layout_id(header: &[u8]) -> String {
+    let mut layout = format!("T{:02x}{:02x}{:02x}", header[3], header[6], header[7]);
+    if header[7] == MSG_SMART_METER || header[7] == MSG_BUFFERED_SMART_METER {
         layout.push('X');
     }
 
-    match layout.as_str() {
-        "T065004X" => t065004x(),
-        _ => t06nnnnx(0),
+    layout
+}
+
+pub fn detect_layout(header: &[u8; 8]) -> LayoutSpecification {
+    if let Some(external) = EXTERNAL_LAYOUTS.get() {
+        if let Some((_, spec)) = external.iter().find(|(rule, _)| rule.matches(header)) {
+            return spec.clone();
+        }
+    }
+
+    let layout = layout_id(header);
+    let is_smart_meter = layout.ends_with('X');
+
+    match (layout.as_str(), INVERTER_TYPE.get().copied().unwrap_or_default()) {
+        ("T065004X", _) => t065004x(),
+        _ if is_smart_meter => t06nnnnxsmt(),
+        (_, InverterType::Sph) => t06nnnnxsph(),
+        (_, InverterType::Spa) => t06nnnnxspa(),
+        (_, InverterType::Default) => t06nnnnx(0),
+    }
+}
+
+/// Header match rule of an external layout, unset parts match any value
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderMatch {
+    pub protocol: Option<u8>,
+    pub device: Option<u8>,
+    pub message_type: Option<u8>,
+}
+
+impl HeaderMatch {
+    pub fn matches(&self, header: &[u8; 8]) -> bool {
+        self.protocol.map_or(true, |protocol| protocol == header[3])
+            && self.device.map_or(true, |device| device == header[6])
+            && self.message_type.map_or(true, |message_type| message_type == header[7])
+    }
+}
+
+// layouts loaded from the layout file, checked before the built-in layouts
+static EXTERNAL_LAYOUTS: OnceLock<Vec<(HeaderMatch, LayoutSpecification)>> = OnceLock::new();
+
+/// Loads the layout file and adds its layouts to the built-in layouts, can only be done once
+pub fn load_layouts(path: &Path) -> Result<(), ProxyError> {
+    let json = std::fs::read_to_string(path)?;
+    let layouts = parse_layouts(&json)
+        .map_err(|err| ProxyError::RuntimeError(format!("Invalid layout file '{}': {err}", path.display())))?;
+
+    log::info!("Loaded {} layouts from '{}'", layouts.len(), path.display());
+    EXTERNAL_LAYOUTS
+        .set(layouts)
+        .map_err(|_| ProxyError::RuntimeError(String::from("Layouts already loaded")))
+}
+
+/// Parses a layout file:
+///
+/// {"layouts": [{"id": "T065104", "decrypt": true, "match": {"protocol": 6, "device": 1, "type": 4},
+///   "fields": [{"name": "pvstatus", "offset": 158, "length": 2, "type": "number", "divide": 1}, ...]}]}
+///
+/// Like the built-in layouts the field offsets count hex characters of the payload (as in the grott layouts),
+/// the lengths count bytes. Field types are "text", "date", "number", "signed", "bitfield" (with a "bits" object
+/// naming the bits) and "enum" (with a "values" object naming the values).
+pub fn parse_layouts(json: &str) -> Result<Vec<(HeaderMatch, LayoutSpecification)>, ProxyError> {
+    let error = |msg: String| ProxyError::RuntimeError(msg);
+
+    let value: Value = serde_json::from_str(json).map_err(|err| error(format!("Invalid json: {err}")))?;
+    let Some(entries) = value.get("layouts").and_then(Value::as_array) else {
+        return Err(error(String::from("'layouts' must be an array")));
+    };
+
+    let mut layouts = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let id = entry.get("id").and_then(Value::as_str).unwrap_or_default();
+        let context = format!("layouts[{index}] ({id})");
+
+        let layout = parse_layout(entry).map_err(|msg| error(format!("{context}: {msg}")))?;
+        layouts.push(layout);
+    }
+
+    Ok(layouts)
+}
+
+fn parse_layout(entry: &Value) -> Result<(HeaderMatch, LayoutSpecification), String> {
+    let id = match entry.get("id") {
+        Some(Value::String(id)) if !id.is_empty() => id,
+        _ => return Err(String::from("'id' must be a non empty string")),
+    };
+
+    let decrypt = match entry.get("decrypt") {
+        None => true,
+        Some(decrypt) => decrypt.as_bool().ok_or("'decrypt' must be a boolean")?,
+    };
+
+    let rule = match entry.get("match") {
+        Some(Value::Object(rule)) => {
+            let byte = |key: &str| -> Result<Option<u8>, String> {
+                match rule.get(key) {
+                    None => Ok(None),
+                    Some(value) => value
+                        .as_u64()
+                        .and_then(|value| u8::try_from(value).ok())
+                        .map(Some)
+                        .ok_or(format!("'match.{key}' must be a byte value")),
+                }
+            };
+
+            HeaderMatch {
+                protocol: byte("protocol")?,
+                device: byte("device")?,
+                message_type: byte("type")?,
+            }
+        }
+        _ => return Err(String::from("'match' must be an object")),
+    };
+
+    let Some(entries) = entry.get("fields").and_then(Value::as_array) else {
+        return Err(String::from("'fields' must be an array"));
+    };
+
+    let mut names = HashSet::new();
+    let mut fields = Vec::new();
+    for (index, field) in entries.iter().enumerate() {
+        let name = field.get("name").and_then(Value::as_str).unwrap_or_default();
+        let spec = parse_field(field).map_err(|msg| format!("fields[{index}] ({name}): {msg}"))?;
+
+        if !names.insert(name) {
+            return Err(format!("fields[{index}] ({name}): duplicate field name"));
+        }
+
+        fields.push(spec);
+    }
+
+    Ok((rule, LayoutSpecification::new(id, decrypt, fields)))
+}
+
+fn parse_field(field: &Value) -> Result<FieldSpecification, String> {
+    let name = match field.get("name") {
+        Some(Value::String(name)) if !name.is_empty() => name,
+        _ => return Err(String::from("'name' must be a non empty string")),
+    };
+
+    let number = |key: &str| -> Result<Option<u64>, String> {
+        match field.get(key) {
+            None => Ok(None),
+            Some(value) => value
+                .as_u64()
+                .map(Some)
+                .ok_or(format!("'{key}' must be a positive integer")),
+        }
+    };
+
+    let offset = number("offset")?.ok_or("'offset' is required")? as usize;
+    if offset % 2 != 0 {
+        return Err(String::from("'offset' counts hex characters and must be even"));
+    }
+
+    let length = number("length")?;
+    let field_type = field.get("type").and_then(Value::as_str).ok_or("'type' is required")?;
+    match field_type {
+        "text" => {
+            return match length {
+                Some(length) if length > 0 => Ok(FieldSpecification::text(name, offset, length as usize)),
+                _ => Err(String::from("text fields require a 'length'")),
+            }
+        }
+        "date" => return Ok(FieldSpecification::date(name, offset)),
+        "number" | "signed" | "bitfield" | "enum" => {}
+        other => return Err(format!("unknown type '{other}'")),
+    }
+
+    let length = length.unwrap_or(2) as usize;
+    if ![1, 2, 4, 8].contains(&length) {
+        return Err(format!("invalid number length {length}, expected 1, 2, 4 or 8"));
+    }
+
+    // the "bits" and "values" names are json objects keyed by the bit or the value
+    let names = |key: &str| -> Result<Vec<(i64, &str)>, String> {
+        let Some(Value::Object(map)) = field.get(key) else {
+            return Err(format!("'{key}' must be an object"));
+        };
+
+        map.iter()
+            .map(|(value, name)| match (value.parse::<i64>(), name.as_str()) {
+                (Ok(value), Some(name)) => Ok((value, name)),
+                _ => Err(format!("'{key}.{value}' must map a number to a name")),
+            })
+            .collect()
+    };
+
+    match field_type {
+        "bitfield" => {
+            let bits = names("bits")?
+                .into_iter()
+                .map(|(bit, name)| match u32::try_from(bit) {
+                    Ok(bit) if (bit as usize) < length * 8 => Ok((bit, name)),
+                    _ => Err(format!("bit {bit} does not fit in {length} bytes")),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(FieldSpecification::bitfield(name, offset, length, &bits))
+        }
+        "enum" => Ok(FieldSpecification::enumeration(name, offset, length, &names("values")?)),
+        _ => {
+            let divide = match number("divide")? {
+                None => 1,
+                Some(0) => return Err(String::from("'divide' can not be 0")),
+                Some(divide) => i64::try_from(divide).map_err(|_| String::from("'divide' is too large"))?,
+            };
+
+            if field_type == "signed" {
+                Ok(FieldSpecification::signed_number(name, offset, length, divide))
+            } else {
+                Ok(FieldSpecification::number(name, offset, length, divide))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_rational::Rational64;
+
+    use super::{parse_layouts, t06nnnnxsph, HeaderMatch};
+    use crate::{
+        dataprocessor::{FieldValue, GrowattData},
+        framing::{GrowattFrame, MSG_DATA, MSG_SMART_METER},
+    };
+
+    #[test]
+    fn smart_meter_device() {
+        let mut payload = vec![0; 162];
+        payload[..10].copy_from_slice(b"MFK0CE306Q");
+        payload[21..25].copy_from_slice(&2301i32.to_be_bytes());
+        payload[45..49].copy_from_slice(&(-15000i32).to_be_bytes());
+        let mut frame = GrowattFrame::new(MSG_SMART_METER)
+            .serial("DLG0000001")
+            .payload(&payload)
+            .build()
+            .unwrap();
+
+        let gd = GrowattData::from_buffer_auto_detect_layout(&mut frame, None).unwrap();
+        assert_eq!(gd.layout_spec, "T06NNNNXSMT");
+        assert_eq!(gd.device_id().unwrap(), "MFK0CE306Q_meter");
+        assert_eq!(
+            gd.field_value("voltagel1").unwrap(),
+            FieldValue::Number(Rational64::new(2301, 10))
+        );
+        assert_eq!(
+            gd.field_value("activepowerl1").unwrap(),
+            FieldValue::Number(Rational64::from_integer(-1500))
+        );
+    }
+
+    #[test]
+    fn sph_storage_fields() {
+        let mut payload = vec![0; 722];
+        payload[..10].copy_from_slice(b"SPH0000001");
+        payload[111..115].copy_from_slice(&(-1234i32).to_be_bytes());
+        payload[601..603].copy_from_slice(&85u16.to_be_bytes());
+        payload[653..655].copy_from_slice(&(-52i16).to_be_bytes());
+        let mut frame = GrowattFrame::new(MSG_DATA)
+            .serial("DLG0000001")
+            .payload(&payload)
+            .build()
+            .unwrap();
+
+        let gd = GrowattData::from_buffer(&mut frame, &t06nnnnxsph()).unwrap();
+        assert_eq!(gd.serial().unwrap(), "SPH0000001");
+        assert_eq!(
+            gd.field_value("pvpowerout").unwrap(),
+            FieldValue::Number(Rational64::new(-1234, 10))
+        );
+        assert_eq!(
+            gd.field_value("soc").unwrap(),
+            FieldValue::Number(Rational64::from_integer(85))
+        );
+        assert_eq!(
+            gd.field_value("batterytemperature").unwrap(),
+            FieldValue::Number(Rational64::new(-52, 10))
+        );
+    }
+
+    #[test]
+    fn layout_file() {
+        let layouts = parse_layouts(
+            r#"{"layouts": [{"id": "T065104", "match": {"protocol": 6, "type": 4}, "fields": [
+                {"name": "pvserial", "offset": 76, "length": 10, "type": "text"},
+                {"name": "pvpowerout", "offset": 250, "length": 4, "type": "number", "divide": 10}]}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(layouts.len(), 1);
+        let (rule, _) = &layouts[0];
+        assert_eq!(
+            *rule,
+            HeaderMatch {
+                protocol: Some(6),
+                device: None,
+                message_type: Some(4),
+            }
+        );
+        assert!(rule.matches(&[0, 1, 0, 6, 2, 0x41, 1, 4]));
+        assert!(!rule.matches(&[0, 1, 0, 6, 2, 0x41, 1, 3]));
+
+        let err = parse_layouts(
+            r#"{"layouts": [{"id": "ok", "match": {}, "fields": []},
+                {"id": "bad", "match": {}, "fields": [{"name": "pvstatus", "offset": 158, "length": 3, "type": "number"}]}]}"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Runtime Error layouts[1] (bad): fields[0] (pvstatus): invalid number length 3, expected 1, 2, 4 or 8"
+        );
     }
 }
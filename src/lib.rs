@@ -1,8 +1,22 @@
 #![warn(clippy::unwrap_used)]
+pub mod command;
 pub mod dataprocessor;
+pub mod firewall;
+pub mod framing;
+pub mod homeassistant;
+pub mod influx;
 pub mod layouts;
+pub mod message;
+pub mod metrics;
 pub mod mqtt;
+pub mod packet;
 pub mod proxy;
+pub mod queue;
+pub mod reassembly;
+pub mod responder;
+pub mod sink;
+pub mod status;
+pub mod template;
 
 #[cfg(feature = "sniffer")]
 pub mod sniffer;
@@ -10,8 +24,12 @@ pub mod sniffer;
 use std::{array::TryFromSliceError, fmt, io::Write, net::AddrParseError, path::Path, str::Utf8Error};
 
 pub fn dump_packet(data: &[u8], output: &Path) -> Result<(), ProxyError> {
-    let mut file = std::fs::OpenOptions::new().write(true).create(true).open(output)?;
-    file.write_all(&data)?;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)?;
+    file.write_all(data)?;
 
     Ok(())
 }
@@ -20,6 +38,7 @@ pub fn dump_packet(data: &[u8], output: &Path) -> Result<(), ProxyError> {
 pub enum ProxyError {
     NetworkError(String),
     RuntimeError(String),
+    CrcError(String),
     ParseError,
 }
 
@@ -28,6 +47,7 @@ impl fmt::Display for ProxyError {
         match self {
             ProxyError::NetworkError(str) => write!(f, "Network Error {}", str),
             ProxyError::RuntimeError(str) => write!(f, "Runtime Error {}", str),
+            ProxyError::CrcError(str) => write!(f, "Crc Error {}", str),
             ProxyError::ParseError => write!(f, "Parse Error"),
         }
     }
@@ -69,6 +89,13 @@ impl From<Utf8Error> for ProxyError {
     }
 }
 
+#[cfg(feature = "sniffer")]
+impl From<pcap::Error> for ProxyError {
+    fn from(err: pcap::Error) -> Self {
+        ProxyError::NetworkError(format!("Capture error: {err}"))
+    }
+}
+
 impl From<rumqttc::ClientError> for ProxyError {
     fn from(err: rumqttc::ClientError) -> Self {
         ProxyError::RuntimeError(format!("MQTT Error: {err}"))
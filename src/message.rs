@@ -0,0 +1,433 @@
+use std::fmt;
+
+use serde_json::{json, Value};
+
+use crate::{
+    dataprocessor::{GrowattData, HEADER_SIZE},
+    framing::{
+        self, MSG_ANNOUNCE, MSG_BUFFERED_DATA, MSG_BUFFERED_SMART_METER, MSG_DATA, MSG_PING, MSG_READ_DATALOGGER,
+        MSG_READ_REGISTERS, MSG_SET_DATALOGGER, MSG_SMART_METER, MSG_WRITE_REGISTER, MSG_WRITE_REGISTERS,
+        SERIAL_LENGTH,
+    },
+    ProxyError,
+};
+
+// the datalogger register with the clock, the server sets it after the datalogger connects
+pub const DATALOGGER_TIME_REGISTER: u16 = 31;
+
+/// The side that sent a frame, requests and responses share their message type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    FromInverter,
+    FromServer,
+}
+
+/// A frame decoded by its message type. The register messages are sent by the server to the datalogger which
+/// answers them with the same message type, the register values are decoded from the payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GrowattMessage {
+    Announce,
+    Data,
+    BufferedData,
+    SmartMeterData,
+    BufferedSmartMeterData,
+    Ping,
+    /// The server acknowledges an announce or (buffered) data frame
+    Ack {
+        message_type: u8,
+    },
+    ReadRegisters {
+        start: u16,
+        end: u16,
+    },
+    ReadRegistersResponse {
+        start: u16,
+        values: Vec<u16>,
+    },
+    WriteRegister {
+        register: u16,
+        value: u16,
+    },
+    WriteRegisterResponse {
+        register: u16,
+        status: u8,
+    },
+    WriteRegisters {
+        start: u16,
+        values: Vec<u16>,
+    },
+    WriteRegistersResponse {
+        start: u16,
+        end: u16,
+        status: u8,
+    },
+    ReadDatalogger {
+        start: u16,
+        end: u16,
+    },
+    ReadDataloggerResponse {
+        register: u16,
+        value: Vec<u8>,
+    },
+    SetDatalogger {
+        register: u16,
+        value: Vec<u8>,
+    },
+    /// The server sets the datalogger clock, the time is text like "2023-03-01 10:52:14"
+    TimeSync {
+        time: String,
+    },
+    SetDataloggerResponse {
+        register: u16,
+        status: u8,
+    },
+    Unknown {
+        message_type: u8,
+    },
+}
+
+/// The message types that carry a data record that can be parsed with a layout
+pub fn carries_data(message_type: u8) -> bool {
+    matches!(
+        message_type,
+        MSG_DATA | MSG_BUFFERED_DATA | MSG_SMART_METER | MSG_BUFFERED_SMART_METER
+    )
+}
+
+/// The serial of the inverter in an announce, it follows the datalogger serial
+pub fn announced_inverter_serial(frame: &[u8]) -> Option<String> {
+    if frame.len() < HEADER_SIZE || frame[7] != MSG_ANNOUNCE {
+        return None;
+    }
+
+    let start = HEADER_SIZE + framing::serial_block_length(frame[3]);
+    let mut data = frame.get(..start + SERIAL_LENGTH)?.to_vec();
+    if framing::has_crc(frame) {
+        GrowattData::decrypt(&mut data);
+    }
+
+    let serial = std::str::from_utf8(&data[start..]).ok()?.trim_end_matches('\0');
+    (!serial.is_empty()).then(|| String::from(serial))
+}
+
+// Reads the big endian numbers of the register messages
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Result<u8, ProxyError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProxyError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&[u8], ProxyError> {
+        if self.data.len() < length {
+            return Err(ProxyError::ParseError);
+        }
+
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn registers(&mut self) -> Result<Vec<u16>, ProxyError> {
+        let mut values = Vec::with_capacity(self.data.len() / 2);
+        while self.data.len() >= 2 {
+            values.push(self.u16()?);
+        }
+
+        Ok(values)
+    }
+}
+
+impl GrowattMessage {
+    /// Decodes a complete frame, the frame itself is not modified
+    pub fn decode(frame: &[u8], direction: Direction) -> Result<GrowattMessage, ProxyError> {
+        if frame.len() < HEADER_SIZE {
+            return Err(ProxyError::ParseError);
+        }
+
+        GrowattData::validate_integity(frame)?;
+
+        let mut data = frame.to_vec();
+        if framing::has_crc(frame) {
+            GrowattData::decrypt(&mut data);
+            data.truncate(data.len() - 2);
+        }
+
+        let message_type = frame[7];
+        let payload = &data[HEADER_SIZE..];
+        let from_server = direction == Direction::FromServer;
+
+        // the acks only carry a status byte, all other messages start with the datalogger serial
+        if from_server
+            && payload.len() == 1
+            && matches!(
+                message_type,
+                MSG_ANNOUNCE | MSG_DATA | MSG_BUFFERED_DATA | MSG_SMART_METER | MSG_BUFFERED_SMART_METER
+            )
+        {
+            return Ok(GrowattMessage::Ack { message_type });
+        }
+
+        let mut reader = Reader {
+            data: payload
+                .get(framing::serial_block_length(frame[3])..)
+                .unwrap_or_default(),
+        };
+
+        Ok(match (message_type, from_server) {
+            (MSG_ANNOUNCE, _) => GrowattMessage::Announce,
+            (MSG_DATA, _) => GrowattMessage::Data,
+            (MSG_BUFFERED_DATA, _) => GrowattMessage::BufferedData,
+            (MSG_SMART_METER, _) => GrowattMessage::SmartMeterData,
+            (MSG_BUFFERED_SMART_METER, _) => GrowattMessage::BufferedSmartMeterData,
+            (MSG_PING, _) => GrowattMessage::Ping,
+            (MSG_READ_REGISTERS, true) => GrowattMessage::ReadRegisters {
+                start: reader.u16()?,
+                end: reader.u16()?,
+            },
+            (MSG_READ_REGISTERS, false) => {
+                let start = reader.u16()?;
+                let _end = reader.u16()?;
+                GrowattMessage::ReadRegistersResponse {
+                    start,
+                    values: reader.registers()?,
+                }
+            }
+            (MSG_WRITE_REGISTER, true) => GrowattMessage::WriteRegister {
+                register: reader.u16()?,
+                value: reader.u16()?,
+            },
+            (MSG_WRITE_REGISTER, false) => GrowattMessage::WriteRegisterResponse {
+                register: reader.u16()?,
+                status: reader.u8()?,
+            },
+            (MSG_WRITE_REGISTERS, true) => {
+                let start = reader.u16()?;
+                let _end = reader.u16()?;
+                GrowattMessage::WriteRegisters {
+                    start,
+                    values: reader.registers()?,
+                }
+            }
+            (MSG_WRITE_REGISTERS, false) => GrowattMessage::WriteRegistersResponse {
+                start: reader.u16()?,
+                end: reader.u16()?,
+                status: reader.u8()?,
+            },
+            (MSG_READ_DATALOGGER, true) => GrowattMessage::ReadDatalogger {
+                start: reader.u16()?,
+                end: reader.u16()?,
+            },
+            (MSG_READ_DATALOGGER, false) => {
+                let register = reader.u16()?;
+                let length = reader.u16()? as usize;
+                GrowattMessage::ReadDataloggerResponse {
+                    register,
+                    value: reader.bytes(length)?.to_vec(),
+                }
+            }
+            (MSG_SET_DATALOGGER, true) => {
+                let register = reader.u16()?;
+                let length = reader.u16()? as usize;
+                let value = reader.bytes(length)?.to_vec();
+                if register == DATALOGGER_TIME_REGISTER {
+                    GrowattMessage::TimeSync {
+                        time: String::from_utf8_lossy(&value).into_owned(),
+                    }
+                } else {
+                    GrowattMessage::SetDatalogger { register, value }
+                }
+            }
+            (MSG_SET_DATALOGGER, false) => GrowattMessage::SetDataloggerResponse {
+                register: reader.u16()?,
+                status: reader.u8()?,
+            },
+            _ => GrowattMessage::Unknown { message_type },
+        })
+    }
+
+    /// Short name of the message type
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GrowattMessage::Announce => "announce",
+            GrowattMessage::Data => "data",
+            GrowattMessage::BufferedData => "buffered data",
+            GrowattMessage::SmartMeterData => "smart meter data",
+            GrowattMessage::BufferedSmartMeterData => "buffered smart meter data",
+            GrowattMessage::Ping => "ping",
+            GrowattMessage::Ack { .. } => "ack",
+            GrowattMessage::ReadRegisters { .. } => "read registers",
+            GrowattMessage::ReadRegistersResponse { .. } => "read registers response",
+            GrowattMessage::WriteRegister { .. } => "write register",
+            GrowattMessage::WriteRegisterResponse { .. } => "write register response",
+            GrowattMessage::WriteRegisters { .. } => "write registers",
+            GrowattMessage::WriteRegistersResponse { .. } => "write registers response",
+            GrowattMessage::ReadDatalogger { .. } => "read datalogger registers",
+            GrowattMessage::ReadDataloggerResponse { .. } => "read datalogger response",
+            GrowattMessage::SetDatalogger { .. } => "set datalogger register",
+            GrowattMessage::TimeSync { .. } => "time sync",
+            GrowattMessage::SetDataloggerResponse { .. } => "set datalogger response",
+            GrowattMessage::Unknown { .. } => "unknown message",
+        }
+    }
+
+    /// The message as a json object with the kind and the decoded values
+    pub fn to_json(&self) -> Value {
+        let text = |value: &[u8]| String::from_utf8_lossy(value).into_owned();
+        let mut json = match self {
+            GrowattMessage::Ack { message_type } | GrowattMessage::Unknown { message_type } => {
+                json!({ "message_type": message_type })
+            }
+            GrowattMessage::ReadRegisters { start, end } | GrowattMessage::ReadDatalogger { start, end } => {
+                json!({ "start": start, "end": end })
+            }
+            GrowattMessage::ReadRegistersResponse { start, values }
+            | GrowattMessage::WriteRegisters { start, values } => {
+                json!({ "start": start, "values": values })
+            }
+            GrowattMessage::WriteRegister { register, value } => json!({ "register": register, "value": value }),
+            GrowattMessage::WriteRegisterResponse { register, status }
+            | GrowattMessage::SetDataloggerResponse { register, status } => {
+                json!({ "register": register, "status": status })
+            }
+            GrowattMessage::WriteRegistersResponse { start, end, status } => {
+                json!({ "start": start, "end": end, "status": status })
+            }
+            GrowattMessage::ReadDataloggerResponse { register, value }
+            | GrowattMessage::SetDatalogger { register, value } => {
+                json!({ "register": register, "value": text(value) })
+            }
+            GrowattMessage::TimeSync { time } => json!({ "time": time }),
+            _ => json!({}),
+        };
+
+        if let Value::Object(map) = &mut json {
+            map.insert(String::from("kind"), Value::from(self.kind()));
+        }
+        json
+    }
+
+    /// The message carries a data record that can be parsed with a layout
+    pub fn has_data(&self) -> bool {
+        matches!(
+            self,
+            GrowattMessage::Data
+                | GrowattMessage::BufferedData
+                | GrowattMessage::SmartMeterData
+                | GrowattMessage::BufferedSmartMeterData
+        )
+    }
+}
+
+impl fmt::Display for GrowattMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = self.kind();
+        match self {
+            GrowattMessage::Ack { message_type } | GrowattMessage::Unknown { message_type } => {
+                write!(f, "{kind} 0x{message_type:02x}")
+            }
+            GrowattMessage::ReadRegisters { start, end } | GrowattMessage::ReadDatalogger { start, end } => {
+                write!(f, "{kind} {start}-{end}")
+            }
+            GrowattMessage::ReadRegistersResponse { start, values }
+            | GrowattMessage::WriteRegisters { start, values } => {
+                write!(f, "{kind} {start}: {values:?}")
+            }
+            GrowattMessage::WriteRegister { register, value } => write!(f, "{kind} {register}: {value}"),
+            GrowattMessage::WriteRegisterResponse { register, status }
+            | GrowattMessage::SetDataloggerResponse { register, status } => {
+                write!(f, "{kind} {register}: status {status}")
+            }
+            GrowattMessage::WriteRegistersResponse { start, end, status } => {
+                write!(f, "{kind} {start}-{end}: status {status}")
+            }
+            GrowattMessage::ReadDataloggerResponse { register, value }
+            | GrowattMessage::SetDatalogger { register, value } => {
+                write!(f, "{kind} {register}: {}", String::from_utf8_lossy(value))
+            }
+            GrowattMessage::TimeSync { time } => write!(f, "{kind} {time}"),
+            _ => write!(f, "{kind}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{announced_inverter_serial, Direction, GrowattMessage};
+    use crate::{
+        framing::{self, GrowattFrame, MSG_READ_REGISTERS, MSG_SET_DATALOGGER},
+        responder,
+    };
+
+    #[test]
+    fn decode_messages() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        assert_eq!(
+            GrowattMessage::decode(growatt_data, Direction::FromInverter).unwrap(),
+            GrowattMessage::Announce
+        );
+        assert_eq!(announced_inverter_serial(growatt_data).as_deref(), Some("MFK0CE306Q"));
+
+        let ack = responder::server_reply(growatt_data).unwrap();
+        assert_eq!(
+            GrowattMessage::decode(&ack, Direction::FromServer).unwrap(),
+            GrowattMessage::Ack { message_type: 0x03 }
+        );
+
+        let mut meter = growatt_data.to_vec();
+        meter[7] = 0x20;
+        framing::update_crc(&mut meter);
+        let ack = responder::server_reply(&meter).unwrap();
+        assert_eq!(
+            GrowattMessage::decode(&ack, Direction::FromServer).unwrap(),
+            GrowattMessage::Ack { message_type: 0x20 }
+        );
+
+        let read = |payload: &[u8]| {
+            GrowattFrame::new(MSG_READ_REGISTERS)
+                .serial("DLG0000001")
+                .payload(payload)
+                .build()
+                .unwrap()
+        };
+        assert_eq!(
+            GrowattMessage::decode(&read(&[0, 3, 0, 4]), Direction::FromServer).unwrap(),
+            GrowattMessage::ReadRegisters { start: 3, end: 4 }
+        );
+        assert_eq!(
+            GrowattMessage::decode(&read(&[0, 3, 0, 4, 0x13, 0x88, 0, 1]), Direction::FromInverter).unwrap(),
+            GrowattMessage::ReadRegistersResponse {
+                start: 3,
+                values: Vec::from([5000, 1])
+            }
+        );
+        assert!(GrowattMessage::decode(&read(&[0, 3]), Direction::FromServer).is_err());
+
+        let set = GrowattFrame::new(MSG_SET_DATALOGGER)
+            .serial("DLG0000001")
+            .payload(&[0, 4, 0, 1, b'5'])
+            .build()
+            .unwrap();
+        let message = GrowattMessage::decode(&set, Direction::FromServer).unwrap();
+        assert_eq!(message.to_string(), "set datalogger register 4: 5");
+
+        let mut payload = Vec::from([0, 31, 0, 19]);
+        payload.extend_from_slice(b"2023-03-01 10:52:14");
+        let time = GrowattFrame::new(MSG_SET_DATALOGGER)
+            .serial("DLG0000001")
+            .payload(&payload)
+            .build()
+            .unwrap();
+        let message = GrowattMessage::decode(&time, Direction::FromServer).unwrap();
+        assert_eq!(
+            message.to_json().to_string(),
+            r#"{"time":"2023-03-01 10:52:14","kind":"time sync"}"#
+        );
+    }
+}
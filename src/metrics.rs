@@ -0,0 +1,264 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    dataprocessor::{FieldValue, GrowattData},
+    ProxyError,
+};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// last value by serial and layout
+type Series = BTreeMap<(String, String), f64>;
+
+/// Process wide counters and the last received inverter values, exported in the Prometheus text format
+pub struct Metrics {
+    pub connections_accepted: AtomicU64,
+    pub frames_parsed: AtomicU64,
+    pub crc_failures: AtomicU64,
+    pub bytes_to_server: AtomicU64,
+    pub bytes_to_inverter: AtomicU64,
+    pub mqtt_publish_failures: AtomicU64,
+    pub commands_blocked: AtomicU64,
+    // ignored frames by layout
+    frames_ignored: Mutex<BTreeMap<String, u64>>,
+    // metric series by field name
+    values: Mutex<BTreeMap<String, Series>>,
+}
+
+static METRICS: Metrics = Metrics::new();
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            connections_accepted: AtomicU64::new(0),
+            frames_parsed: AtomicU64::new(0),
+            crc_failures: AtomicU64::new(0),
+            bytes_to_server: AtomicU64::new(0),
+            bytes_to_inverter: AtomicU64::new(0),
+            mqtt_publish_failures: AtomicU64::new(0),
+            commands_blocked: AtomicU64::new(0),
+            frames_ignored: Mutex::new(BTreeMap::new()),
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, value: usize) {
+        counter.fetch_add(value as u64, Ordering::Relaxed);
+    }
+
+    pub fn frame_ignored(&self, layout: &str) {
+        if let Ok(mut ignored) = self.frames_ignored.lock() {
+            *ignored.entry(String::from(layout)).or_default() += 1;
+        }
+    }
+
+    /// Stores the numeric fields of the data as the current inverter values, buffered data is history and is skipped
+    pub fn record_data(&self, data: &GrowattData) {
+        if data.is_buffered() {
+            return;
+        }
+
+        let key = (data.serial().unwrap_or_default(), data.layout());
+
+        if let Ok(mut values) = self.values.lock() {
+            for field in &data.fields {
+                if let FieldValue::Number(num) = &field.value {
+                    let value = *num.numer() as f64 / *num.denom() as f64;
+                    values
+                        .entry(metric_name(&field.name))
+                        .or_default()
+                        .insert(key.clone(), value);
+                }
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "growattproxy_connections_accepted_total",
+                "Inverter connections accepted",
+                &self.connections_accepted,
+            ),
+            (
+                "growattproxy_frames_parsed_total",
+                "Inverter frames parsed",
+                &self.frames_parsed,
+            ),
+            (
+                "growattproxy_crc_failures_total",
+                "Frames with an invalid crc",
+                &self.crc_failures,
+            ),
+            (
+                "growattproxy_bytes_forwarded_to_server_total",
+                "Bytes forwarded to the Growatt server",
+                &self.bytes_to_server,
+            ),
+            (
+                "growattproxy_bytes_forwarded_to_inverter_total",
+                "Bytes forwarded to the inverter",
+                &self.bytes_to_inverter,
+            ),
+            (
+                "growattproxy_mqtt_publish_failures_total",
+                "MQTT messages the client could not queue and MQTT connection errors",
+                &self.mqtt_publish_failures,
+            ),
+            (
+                "growattproxy_commands_blocked_total",
+                "Growatt server commands dropped by the firewall",
+                &self.commands_blocked,
+            ),
+        ];
+
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        if let Ok(ignored) = self.frames_ignored.lock() {
+            let name = "growattproxy_frames_ignored_total";
+            let _ = writeln!(out, "# HELP {name} Frames without power data that were ignored");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (layout, count) in ignored.iter() {
+                let _ = writeln!(out, "{name}{{layout=\"{}\"}} {count}", escape_label(layout));
+            }
+        }
+
+        if let Ok(values) = self.values.lock() {
+            for (name, series) in values.iter() {
+                let _ = writeln!(out, "# TYPE {name} gauge");
+                for ((serial, layout), value) in series {
+                    let _ = writeln!(
+                        out,
+                        "{name}{{serial=\"{}\",layout=\"{}\"}} {value}",
+                        escape_label(serial),
+                        escape_label(layout)
+                    );
+                }
+            }
+        }
+
+        out
+    }
+}
+
+fn metric_name(field: &str) -> String {
+    let name: String = field
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("growatt_{name}")
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves the metrics over http on the given address
+pub async fn serve(address: &str) -> Result<(), ProxyError> {
+    let listener = TcpListener::bind(address).await?;
+    log::info!("Serving metrics on http://{address}/metrics");
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(err) = handle_request(socket).await {
+                log::debug!("Metrics request failed: {err}");
+            }
+        });
+    }
+}
+
+async fn handle_request(mut socket: TcpStream) -> Result<(), ProxyError> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 || request.len() > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics().render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    };
+
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use crate::{dataprocessor::GrowattData, layouts};
+
+    #[test]
+    fn render_values() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        let mut data = growatt_data.to_vec();
+        let gd = GrowattData::from_buffer(&mut data, &layouts::t065004x()).unwrap();
+
+        let metrics = Metrics::new();
+        Metrics::increment(&metrics.frames_parsed);
+        metrics.frame_ignored("T065103");
+        metrics.record_data(&gd);
+
+        // the buffered frames the inverter replays do not replace the last values
+        let mut buffered = growatt_data.to_vec();
+        buffered[7] = 0x50;
+        crate::framing::update_crc(&mut buffered);
+        let buffered = GrowattData::from_buffer(&mut buffered, &layouts::t065004x()).unwrap();
+        assert!(buffered.is_buffered());
+        metrics.record_data(&buffered);
+
+        let output = metrics.render();
+        assert!(output.contains("growattproxy_frames_parsed_total 1\n"));
+        assert!(output.contains("growattproxy_frames_ignored_total{layout=\"T065103\"} 1\n"));
+        assert!(output.contains("growatt_pvstatus{serial=\"MFK0CE306Q\",layout=\"T065103\"} 1\n"));
+        assert!(!output.contains("growatt_pvserial"));
+        assert!(!output.contains(&format!("layout=\"{}\"", buffered.layout())));
+    }
+}
@@ -1,17 +1,206 @@
 use chrono::SecondsFormat;
 use rumqttc::Event::Incoming;
-use rumqttc::{AsyncClient, Client, MqttOptions, Packet, QoS};
+use rumqttc::{AsyncClient, EventLoop, Key, LastWill, MqttOptions, Packet, Publish, QoS, Transport};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use crate::{
+    command::{self, Command, RegisterAllowList},
     dataprocessor::{FieldValue, GrowattData},
+    homeassistant,
+    message::GrowattMessage,
+    metrics::{self, Metrics},
+    sink::DataSink,
+    status::StatusChange,
+    template::{self, PayloadTemplate},
     ProxyError,
 };
 
+// a data frame can produce a message per field plus the discovery configurations
+const REQUEST_QUEUE_SIZE: usize = 1000;
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
 #[derive(Clone)]
 pub struct MqttConfig {
     pub server: String,
     pub port: u16,
+    /// Topic pattern, see `template::expand` for the supported placeholders
+    pub topic: String,
+    pub payload: PayloadTemplate,
+    /// Root of the topics owned by the proxy, e.g. the state topic of the discovered sensors
+    pub base_topic: String,
+    /// Home Assistant discovery prefix, discovery is disabled when not set
+    pub discovery_prefix: Option<String>,
+    pub client_id: String,
+    pub keep_alive: Duration,
+    pub credentials: Option<(String, String)>,
+    pub tls: Option<MqttTlsConfig>,
+    pub qos: QoS,
+    /// Retain flag of the data messages
+    pub retain: bool,
+    /// Also publish every field as a bare value on its own topic
+    pub field_topics: bool,
+    /// Accept register commands on `<base_topic>/<serial>/command`, writes are limited to the allowed registers
+    pub commands: Option<RegisterAllowList>,
+}
+
+#[derive(Clone, Default)]
+pub struct MqttTlsConfig {
+    /// CA certificate (PEM) used to verify the broker, the platform certificates are used when not set
+    pub ca_cert: Option<PathBuf>,
+    /// Client certificate and private key (PEM) for brokers that require client authentication
+    pub client_auth: Option<(PathBuf, PathBuf)>,
+}
+
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+/// The MQTT command line options shared by the binaries
+#[derive(clap::Args, Debug)]
+pub struct MqttArgs {
+    // set the mqtt addr
+    #[clap(long = "mqtt-addr", env = "GP_MQTT_ADDRESS")]
+    pub mqtt_addr: Option<String>,
+
+    #[clap(long = "mqtt-port", env = "GP_MQTT_PORT", default_value_t = 1883)]
+    pub mqtt_port: u16,
+
+    // topic to publish on, {serial} and {layout} are replaced by the inverter serial and layout
+    #[clap(long = "mqtt-topic", env = "GP_MQTT_TOPIC")]
+    pub mqtt_topic: Option<String>,
+
+    // payload format: 'fields', 'remi' or the path of a json payload template
+    #[clap(long = "mqtt-payload", env = "GP_MQTT_PAYLOAD")]
+    pub mqtt_payload: Option<String>,
+
+    // root topic for the topics published by the proxy itself
+    #[clap(long = "mqtt-base-topic", env = "GP_MQTT_BASE_TOPIC", default_value = "growatt")]
+    pub mqtt_base_topic: String,
+
+    // publish home assistant discovery configurations
+    #[clap(long = "mqtt-discovery", env = "GP_MQTT_DISCOVERY", default_value_t = false)]
+    pub mqtt_discovery: bool,
+
+    #[clap(
+        long = "mqtt-discovery-prefix",
+        env = "GP_MQTT_DISCOVERY_PREFIX",
+        default_value = "homeassistant"
+    )]
+    pub mqtt_discovery_prefix: String,
+
+    #[clap(long = "mqtt-username", env = "GP_MQTT_USERNAME")]
+    pub mqtt_username: Option<String>,
+
+    #[clap(long = "mqtt-password", env = "GP_MQTT_PASSWORD")]
+    pub mqtt_password: Option<String>,
+
+    // connect to the broker using tls, implied by the certificate options
+    #[clap(long = "mqtt-tls", env = "GP_MQTT_TLS", default_value_t = false)]
+    pub mqtt_tls: bool,
+
+    #[clap(long = "mqtt-ca-cert", env = "GP_MQTT_CA_CERT")]
+    pub mqtt_ca_cert: Option<PathBuf>,
+
+    #[clap(long = "mqtt-client-cert", env = "GP_MQTT_CLIENT_CERT", requires = "mqtt_client_key")]
+    pub mqtt_client_cert: Option<PathBuf>,
+
+    #[clap(long = "mqtt-client-key", env = "GP_MQTT_CLIENT_KEY", requires = "mqtt_client_cert")]
+    pub mqtt_client_key: Option<PathBuf>,
+
+    #[clap(long = "mqtt-client-id", env = "GP_MQTT_CLIENT_ID", default_value = "growattproxy")]
+    pub mqtt_client_id: String,
+
+    // keep alive interval in seconds
+    #[clap(long = "mqtt-keep-alive", env = "GP_MQTT_KEEP_ALIVE", default_value_t = 25)]
+    pub mqtt_keep_alive: u64,
+
+    #[clap(long = "mqtt-qos", env = "GP_MQTT_QOS", default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub mqtt_qos: u8,
+
+    #[clap(long = "mqtt-retain", env = "GP_MQTT_RETAIN", default_value_t = false)]
+    pub mqtt_retain: bool,
+
+    // publish every field on its own topic: <base-topic>/<serial>/<field>
+    #[clap(long = "mqtt-field-topics", env = "GP_MQTT_FIELD_TOPICS", default_value_t = false)]
+    pub mqtt_field_topics: bool,
+
+    // only the proxy sends commands, the sniffer ignores this option
+    #[clap(
+        long = "mqtt-commands",
+        env = "GP_MQTT_COMMANDS",
+        default_value_t = false,
+        help = "Accept register read and write commands on <base-topic>/<serial>/command, retained commands are \
+                rejected. The inverter stream is forwarded as is, so the Growatt server also receives the responses \
+                to these commands"
+    )]
+    pub mqtt_commands: bool,
+
+    // registers that may be written by a command, e.g. "3,122,300-305", nothing can be written when not set
+    #[clap(
+        long = "mqtt-write-registers",
+        env = "GP_MQTT_WRITE_REGISTERS",
+        requires = "mqtt_commands"
+    )]
+    pub mqtt_write_registers: Option<RegisterAllowList>,
+}
+
+impl MqttArgs {
+    /// Builds the configuration, the defaults are used for the options that were not provided
+    pub fn config(&self, default_topic: &str, default_payload: &str) -> Result<Option<MqttConfig>, ProxyError> {
+        let Some(server) = &self.mqtt_addr else {
+            return Ok(None);
+        };
+
+        Ok(Some(MqttConfig {
+            server: server.clone(),
+            port: self.mqtt_port,
+            topic: self.mqtt_topic.clone().unwrap_or_else(|| String::from(default_topic)),
+            payload: PayloadTemplate::load(self.mqtt_payload.as_deref().unwrap_or(default_payload))?,
+            base_topic: self.mqtt_base_topic.clone(),
+            discovery_prefix: self.mqtt_discovery.then(|| self.mqtt_discovery_prefix.clone()),
+            client_id: self.mqtt_client_id.clone(),
+            keep_alive: Duration::from_secs(self.mqtt_keep_alive),
+            credentials: self
+                .mqtt_username
+                .as_ref()
+                .map(|user| (user.clone(), self.mqtt_password.clone().unwrap_or_default())),
+            tls: self.tls_config(),
+            qos: match self.mqtt_qos {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                _ => QoS::ExactlyOnce,
+            },
+            retain: self.mqtt_retain,
+            field_topics: self.mqtt_field_topics,
+            commands: self
+                .mqtt_commands
+                .then(|| self.mqtt_write_registers.clone().unwrap_or_default()),
+        }))
+    }
+
+    fn tls_config(&self) -> Option<MqttTlsConfig> {
+        let client_auth = match (&self.mqtt_client_cert, &self.mqtt_client_key) {
+            (Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+            _ => None,
+        };
+
+        if !self.mqtt_tls && self.mqtt_ca_cert.is_none() && client_auth.is_none() {
+            return None;
+        }
+
+        Some(MqttTlsConfig {
+            ca_cert: self.mqtt_ca_cert.clone(),
+            client_auth,
+        })
+    }
 }
 
 pub fn field_value_to_json_value(val: &FieldValue, factor: Option<f64>) -> Option<serde_json::Value> {
@@ -44,112 +233,351 @@ pub fn field_value_to_json_value(val: &FieldValue, factor: Option<f64>) -> Optio
     None
 }
 
-fn growatt_data_json_remi(data: &GrowattData) -> String {
-    use serde_json::{Map, Number, Value};
+/// Long lived MQTT session shared by all the publishers of the process.
+/// The event loop runs on a background thread and reconnects to the broker with an exponential backoff.
+#[derive(Clone)]
+pub struct MqttClient {
+    client: AsyncClient,
+    cfg: Arc<MqttConfig>,
+    // serials for which the discovery configuration was published
+    discovered: Arc<Mutex<HashSet<String>>>,
+}
 
-    let mut map = Map::new();
+impl MqttClient {
+    pub fn new(cfg: &MqttConfig) -> Result<MqttClient, ProxyError> {
+        let mut mqttoptions = MqttOptions::new(cfg.client_id.as_str(), cfg.server.as_str(), cfg.port);
+        mqttoptions.set_keep_alive(cfg.keep_alive);
 
-    map.insert(String::from("ident"), Value::String(String::from("pvpanelendak")));
-    map.insert(String::from("device_CH"), Value::Number(Number::from(1)));
-    map.insert(String::from("Name"), Value::String(String::from("PV")));
-    map.insert(String::from("CHname"), Value::String(String::from("PV")));
-    map.insert(String::from("Type"), Value::String(String::from("MB")));
-    map.insert(String::from("Units"), Value::String(String::from("kWh")));
+        if let Some((username, password)) = &cfg.credentials {
+            mqttoptions.set_credentials(username, password);
+        }
 
-    if let Some(field) = data.field_value("pvgridvoltage") {
-        if let Some(field_val) = field_value_to_json_value(&field, None) {
-            map.insert(String::from("U"), field_val);
+        if let Some(tls) = &cfg.tls {
+            mqttoptions.set_transport(tls_transport(tls)?);
         }
+
+        // the broker marks the proxy offline when the connection is lost
+        let status_topic = availability_topic(&cfg.base_topic, None);
+        mqttoptions.set_last_will(LastWill::new(&status_topic, OFFLINE, QoS::AtLeastOnce, true));
+
+        let (client, eventloop) = AsyncClient::new(mqttoptions, REQUEST_QUEUE_SIZE);
+        let cfg = Arc::new(cfg.clone());
+        let (loop_client, loop_cfg) = (client.clone(), cfg.clone());
+        std::thread::Builder::new()
+            .name(String::from("mqtt"))
+            .spawn(move || run_event_loop(eventloop, loop_client, loop_cfg, status_topic))?;
+
+        Ok(MqttClient {
+            client,
+            cfg,
+            discovered: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    pub async fn publish(&self, msg: MqttMessage) -> Result<(), ProxyError> {
+        self.client
+            .publish(msg.topic, self.cfg.qos, msg.retain, msg.payload)
+            .await
+            .map_err(count_failure)
+    }
+
+    /// Non blocking publish for synchronous callers, fails when the request queue is full
+    pub fn try_publish(&self, msg: MqttMessage) -> Result<(), ProxyError> {
+        self.client
+            .try_publish(msg.topic, self.cfg.qos, msg.retain, msg.payload)
+            .map_err(count_failure)
+    }
+
+    /// Publishes the retained online state of an inverter
+    pub fn publish_availability(&self, serial: Option<&str>, online: bool) -> Result<(), ProxyError> {
+        self.try_publish(MqttMessage {
+            topic: availability_topic(&self.cfg.base_topic, serial),
+            payload: String::from(if online { ONLINE } else { OFFLINE }),
+            retain: true,
+        })
     }
 
-    if let Some(field) = data.field_value("pvgridcurrent") {
-        if let Some(field_val) = field_value_to_json_value(&field, Some(1000.0)) {
-            map.insert(String::from("I"), field_val);
+    /// All the messages that need to be published for the data
+    fn data_messages(&self, data: &GrowattData) -> Vec<MqttMessage> {
+        let cfg = &self.cfg;
+        let mut messages = Vec::from([MqttMessage {
+            topic: template::expand(&cfg.topic, data),
+            payload: cfg.payload.render(data),
+            retain: cfg.retain,
+        }]);
+
+        if let (true, Some(device)) = (cfg.field_topics, data.device_id()) {
+            messages.extend(field_messages(
+                data,
+                &format!("{}/{device}", cfg.base_topic),
+                cfg.retain,
+            ));
+        }
+
+        if let (Some(prefix), Some(serial), Some(device)) = (&cfg.discovery_prefix, data.serial(), data.device_id()) {
+            let state_topic = format!("{}/{device}/state", cfg.base_topic);
+
+            let first_seen = match self.discovered.lock() {
+                Ok(mut discovered) => discovered.insert(device),
+                Err(_) => false,
+            };
+
+            if first_seen {
+                let availability = [
+                    availability_topic(&cfg.base_topic, None),
+                    availability_topic(&cfg.base_topic, Some(&serial)),
+                ];
+                messages.extend(homeassistant::discovery_messages(
+                    data,
+                    prefix,
+                    &state_topic,
+                    &availability,
+                ));
+            }
+
+            messages.push(MqttMessage {
+                topic: state_topic,
+                payload: PayloadTemplate::Fields.render(data),
+                retain: cfg.retain,
+            });
         }
+
+        messages
+    }
+}
+
+impl DataSink for MqttClient {
+    fn name(&self) -> &str {
+        "mqtt"
     }
 
-    if let Some(field) = data.field_value("pvpowerout") {
-        if let Some(field_val) = field_value_to_json_value(&field, None) {
-            map.insert(String::from("P"), field_val);
+    fn publish(&self, data: &GrowattData) -> Result<(), ProxyError> {
+        for msg in self.data_messages(data) {
+            self.try_publish(msg)?;
         }
+
+        Ok(())
+    }
+
+    fn inverter_availability(&self, serial: &str, online: bool) -> Result<(), ProxyError> {
+        self.publish_availability(Some(serial), online)
     }
 
-    map.insert(String::from("HC"), Value::Number(Number::from(0)));
-    map.insert(String::from("DC"), Value::Number(Number::from(0)));
-    map.insert(String::from("MC"), Value::Number(Number::from(0)));
-    map.insert(String::from("CH"), Value::Number(Number::from(0)));
-    map.insert(String::from("CL"), Value::Number(Number::from(0)));
+    fn command_response(&self, serial: &str, response: &serde_json::Value) -> Result<(), ProxyError> {
+        self.try_publish(MqttMessage {
+            topic: command_response_topic(&self.cfg.base_topic, serial),
+            payload: response.to_string(),
+            retain: false,
+        })
+    }
+
+    fn server_message(&self, serial: &str, message: &GrowattMessage, forwarded: bool) -> Result<(), ProxyError> {
+        // the acks and pings are only interesting in the data log
+        if matches!(message, GrowattMessage::Ack { .. } | GrowattMessage::Ping) {
+            return Ok(());
+        }
 
-    if let Some(field) = data.field_value("pvenergytoday") {
-        if let Some(field_val) = field_value_to_json_value(&field, Some(1000.0)) {
-            map.insert(String::from("DC"), field_val);
+        let mut payload = message.to_json();
+        if let serde_json::Value::Object(map) = &mut payload {
+            map.insert(String::from("forwarded"), serde_json::Value::from(forwarded));
         }
+
+        self.try_publish(MqttMessage {
+            topic: format!("{}/{serial}/server", self.cfg.base_topic),
+            payload: payload.to_string(),
+            retain: false,
+        })
+    }
+
+    fn status_changed(&self, change: &StatusChange) -> Result<(), ProxyError> {
+        self.try_publish(MqttMessage {
+            topic: format!("{}/{}/event", self.cfg.base_topic, change.serial),
+            payload: serde_json::json!({
+                "event": "status",
+                "previous": change.previous,
+                "status": change.status,
+            })
+            .to_string(),
+            retain: false,
+        })
     }
+}
+
+fn tls_transport(tls: &MqttTlsConfig) -> Result<Transport, ProxyError> {
+    let client_auth = match &tls.client_auth {
+        Some((cert, key)) => {
+            let key = std::fs::read(key)?;
+            // rsa keys are only supported in the pkcs1 format, pkcs8 keys are handled by the ecc loader
+            let key = if key.windows(15).any(|w| w == b"RSA PRIVATE KEY") {
+                Key::RSA(key)
+            } else {
+                Key::ECC(key)
+            };
 
-    if let Some(field) = data.field_value("pvenergytotal") {
-        if let Some(field_val) = field_value_to_json_value(&field, Some(1000.0)) {
-            map.insert(String::from("CH"), field_val);
+            Some((std::fs::read(cert)?, key))
         }
+        None => None,
+    };
+
+    match &tls.ca_cert {
+        Some(ca) => Ok(Transport::tls(std::fs::read(ca)?, client_auth, None)),
+        None if client_auth.is_some() => Err(ProxyError::RuntimeError(String::from(
+            "MQTT client certificates require a CA certificate",
+        ))),
+        None => Ok(Transport::tls_with_default_config()),
     }
+}
 
-    Value::Object(map).to_string()
+fn count_failure(err: rumqttc::ClientError) -> ProxyError {
+    Metrics::increment(&metrics::metrics().mqtt_publish_failures);
+    err.into()
 }
 
-fn growatt_data_json(data: &GrowattData) -> String {
-    use serde_json::{Map, Value};
+/// Every field as a bare value on a topic below the given root, strings are published without json quotes
+fn field_messages(data: &GrowattData, root: &str, retain: bool) -> Vec<MqttMessage> {
+    data.fields
+        .iter()
+        .filter_map(|field| {
+            let payload = match field_value_to_json_value(&field.value, None)? {
+                serde_json::Value::String(str) => str,
+                value => value.to_string(),
+            };
 
-    let mut map = Map::new();
+            Some(MqttMessage {
+                topic: format!("{root}/{}", field.name),
+                payload,
+                retain,
+            })
+        })
+        .collect()
+}
 
-    for field in &data.fields {
-        if let Some(field_val) = field_value_to_json_value(&field.value, None) {
-            map.insert(field.name.clone(), field_val);
-        }
+/// Topic with the retained online state of the proxy or, when a serial is given, of an inverter
+pub fn availability_topic(base_topic: &str, serial: Option<&str>) -> String {
+    match serial {
+        Some(serial) => format!("{base_topic}/{serial}/status"),
+        None => format!("{base_topic}/status"),
     }
+}
 
-    Value::Object(map).to_string()
+fn command_response_topic(base_topic: &str, serial: &str) -> String {
+    format!("{base_topic}/{serial}/command/response")
 }
 
-pub async fn publish_data(data: &GrowattData, cfg: &MqttConfig) -> Result<(), ProxyError> {
-    let mut mqttoptions = MqttOptions::new("growattproxy", cfg.server.as_str(), cfg.port);
-    mqttoptions.set_keep_alive(Duration::from_secs(25));
+// Hands a command topic message to the session of the inverter, errors are published as the command response
+fn handle_command(client: &AsyncClient, cfg: &MqttConfig, publish: &Publish) {
+    let Some(allowed) = &cfg.commands else {
+        return;
+    };
 
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
-    client
-        .publish(
-            "pvpanelendak/PUB/CH1",
-            QoS::AtLeastOnce,
-            false,
-            growatt_data_json_remi(&data),
-        )
-        .await?;
+    let Some(serial) = publish
+        .topic
+        .strip_prefix(&format!("{}/", cfg.base_topic))
+        .and_then(|topic| topic.strip_suffix("/command"))
+    else {
+        return;
+    };
 
-    loop {
-        let notification = eventloop.poll().await?;
-        if let Incoming(msg) = notification {
-            if let Packet::PubAck(_) = msg {
-                break;
-            }
+    // the broker delivers a retained message again on every subscribe, a write would be repeated on each reconnect
+    if publish.retain {
+        log::warn!("Ignored retained command for inverter {serial}");
+        publish_command_error(client, cfg, serial, "Retained commands are not executed");
+        return;
+    }
+
+    let result = Command::parse(&publish.payload).and_then(|command| match command {
+        Command::WriteRegister { register, .. } if !allowed.allows(register) => Err(ProxyError::RuntimeError(format!(
+            "Register {register} is not allowed to be written"
+        ))),
+        command => {
+            log::info!("Command for inverter {serial}: {command:?}");
+            command::router().send(serial, command)
         }
+    });
+
+    if let Err(err) = result {
+        log::warn!("Rejected command for inverter {serial}: {err}");
+        publish_command_error(client, cfg, serial, &err.to_string());
     }
+}
 
-    Ok(())
+fn publish_command_error(client: &AsyncClient, cfg: &MqttConfig, serial: &str, error: &str) {
+    let response = serde_json::json!({ "error": error });
+    if let Err(err) = client.try_publish(
+        command_response_topic(&cfg.base_topic, serial),
+        QoS::AtLeastOnce,
+        false,
+        response.to_string(),
+    ) {
+        log::warn!("Failed to publish command response: {err}");
+    }
 }
 
-pub fn publish_data_sync(data: &GrowattData, cfg: &MqttConfig) -> Result<(), ProxyError> {
-    let mut mqttoptions = MqttOptions::new("growattproxy", cfg.server.as_str(), cfg.port);
-    mqttoptions.set_keep_alive(Duration::from_secs(25));
+fn run_event_loop(mut eventloop: EventLoop, client: AsyncClient, cfg: Arc<MqttConfig>, status_topic: String) {
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            log::error!("Failed to create MQTT runtime: {err}");
+            return;
+        }
+    };
 
-    let (mut client, mut connection) = Client::new(mqttoptions, 10);
-    client.publish("energy/growattproxy", QoS::AtLeastOnce, false, growatt_data_json(&data))?;
+    runtime.block_on(async move {
+        let mut backoff = MIN_RECONNECT_DELAY;
+        loop {
+            match eventloop.poll().await {
+                Ok(Incoming(Packet::ConnAck(_))) => {
+                    log::info!("Connected to MQTT broker");
+                    backoff = MIN_RECONNECT_DELAY;
+                    if let Err(err) = client.try_publish(&status_topic, QoS::AtLeastOnce, true, ONLINE) {
+                        log::warn!("Failed to publish proxy availability: {err}");
+                    }
 
-    // Wait for the ack
-    for (_, notification) in connection.iter().enumerate() {
-        if let Incoming(msg) = notification.unwrap() {
-            if let Packet::PubAck(_) = msg {
-                break;
+                    if cfg.commands.is_some() {
+                        let topic = format!("{}/+/command", cfg.base_topic);
+                        if let Err(err) = client.try_subscribe(&topic, QoS::AtLeastOnce) {
+                            log::warn!("Failed to subscribe to {topic}: {err}");
+                        }
+                    }
+                }
+                Ok(Incoming(Packet::Publish(publish))) => handle_command(&client, &cfg, &publish),
+                Ok(_) => {}
+                Err(err) => {
+                    // the messages in flight are lost with the connection
+                    Metrics::increment(&metrics::metrics().mqtt_publish_failures);
+                    log::warn!("MQTT connection error: {err} (reconnect in {}s)", backoff.as_secs());
+                    tokio::time::sleep(backoff).await;
+                    backoff = std::cmp::min(backoff * 2, MAX_RECONNECT_DELAY);
+                }
             }
         }
-    }
+    });
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::field_messages;
+    use crate::{dataprocessor::GrowattData, layouts};
+
+    #[test]
+    fn bare_field_values() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        let mut data = growatt_data.to_vec();
+        let gd = GrowattData::from_buffer(&mut data, &layouts::t065004x()).unwrap();
+
+        let messages = field_messages(&gd, "growatt/MFK0CE306Q", false);
+        assert_eq!(messages.len(), gd.field_count());
+
+        let serial = messages
+            .iter()
+            .find(|msg| msg.topic == "growatt/MFK0CE306Q/pvserial")
+            .unwrap();
+        assert_eq!(serial.payload, "MFK0CE306Q");
+
+        let status = messages
+            .iter()
+            .find(|msg| msg.topic == "growatt/MFK0CE306Q/pvstatus")
+            .unwrap();
+        assert_eq!(status.payload, "1");
+    }
 }
@@ -0,0 +1,219 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::ProxyError;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const IP_PROTOCOL_TCP: u8 = 6;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+
+/// The link layer of a capture, the values of the pcap link types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkType {
+    Ethernet,
+    /// Linux cooked capture, used by the "any" device
+    LinuxSll,
+    LinuxSll2,
+}
+
+impl LinkType {
+    pub fn from_dlt(dlt: i32) -> Option<LinkType> {
+        match dlt {
+            1 => Some(LinkType::Ethernet),
+            113 => Some(LinkType::LinuxSll),
+            276 => Some(LinkType::LinuxSll2),
+            _ => None,
+        }
+    }
+}
+
+/// The TCP header fields and payload of a captured packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TcpSegment<'a> {
+    pub source: (IpAddr, u16),
+    pub destination: (IpAddr, u16),
+    pub sequence: u32,
+    pub flags: u8,
+    /// Offset of the payload in the captured packet
+    pub offset: usize,
+    pub payload: &'a [u8],
+}
+
+fn error(msg: &str) -> ProxyError {
+    ProxyError::RuntimeError(String::from(msg))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, ProxyError> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| error("Packet truncated"))
+}
+
+/// Finds the TCP payload of a captured packet by walking the link, IP and TCP headers
+pub fn parse(link: LinkType, packet: &[u8]) -> Result<TcpSegment<'_>, ProxyError> {
+    let (mut ethertype, mut offset) = match link {
+        LinkType::Ethernet => (u16_at(packet, 12)?, 14),
+        LinkType::LinuxSll => (u16_at(packet, 14)?, 16),
+        LinkType::LinuxSll2 => (u16_at(packet, 0)?, 20),
+    };
+
+    while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+        ethertype = u16_at(packet, offset + 2)?;
+        offset += 4;
+    }
+
+    // the ip payload ends at the length in the ip header, ethernet frames can be padded
+    let (source, destination, end) = match ethertype {
+        ETHERTYPE_IPV4 => {
+            let header = packet
+                .get(offset..offset + 20)
+                .ok_or_else(|| error("Packet truncated"))?;
+            let header_length = usize::from(header[0] & 0x0f) * 4;
+            let total_length = usize::from(u16_at(header, 2)?);
+            if header_length < 20 || header_length > total_length {
+                return Err(error("Invalid IPv4 header"));
+            }
+            // more fragments flag or a fragment offset
+            if u16_at(header, 6)? & 0x3fff != 0 {
+                return Err(error("Fragmented IPv4 packets are not supported"));
+            }
+            if header[9] != IP_PROTOCOL_TCP {
+                return Err(error("Not a TCP packet"));
+            }
+
+            let end = offset + total_length;
+            let source = IpAddr::V4(Ipv4Addr::new(header[12], header[13], header[14], header[15]));
+            let destination = IpAddr::V4(Ipv4Addr::new(header[16], header[17], header[18], header[19]));
+            offset += header_length;
+            (source, destination, end)
+        }
+        ETHERTYPE_IPV6 => {
+            let header = packet
+                .get(offset..offset + 40)
+                .ok_or_else(|| error("Packet truncated"))?;
+            let end = offset + 40 + usize::from(u16_at(header, 4)?);
+            let address = |start: usize| -> Result<IpAddr, ProxyError> {
+                let bytes: [u8; 16] = header[start..start + 16].try_into()?;
+                Ok(IpAddr::V6(Ipv6Addr::from(bytes)))
+            };
+            let (source, destination) = (address(8)?, address(24)?);
+
+            let mut next_header = header[6];
+            offset += 40;
+            loop {
+                let extension = packet.get(offset..offset + 2).ok_or_else(|| error("Packet truncated"));
+                match next_header {
+                    IP_PROTOCOL_TCP => break,
+                    // hop by hop, routing and destination options
+                    0 | 43 | 60 => {
+                        let extension = extension?;
+                        next_header = extension[0];
+                        offset += (usize::from(extension[1]) + 1) * 8;
+                    }
+                    // authentication header
+                    51 => {
+                        let extension = extension?;
+                        next_header = extension[0];
+                        offset += (usize::from(extension[1]) + 2) * 4;
+                    }
+                    44 => return Err(error("Fragmented IPv6 packets are not supported")),
+                    _ => return Err(error("Not a TCP packet")),
+                }
+            }
+
+            (source, destination, end)
+        }
+        _ => return Err(error("Not an IP packet")),
+    };
+
+    let tcp = packet
+        .get(offset..offset + 20)
+        .ok_or_else(|| error("Packet truncated"))?;
+    let header_length = usize::from(tcp[12] >> 4) * 4;
+    let start = offset + header_length;
+    let end = end.min(packet.len());
+    if header_length < 20 || start > end {
+        return Err(error("Invalid TCP header"));
+    }
+
+    Ok(TcpSegment {
+        source: (source, u16_at(tcp, 0)?),
+        destination: (destination, u16_at(tcp, 2)?),
+        sequence: u32::from_be_bytes(tcp[4..8].try_into()?),
+        flags: tcp[13],
+        offset: start,
+        payload: &packet[start..end],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{parse, LinkType};
+
+    // the growatt header length matches the captured payload
+    fn is_frame(payload: &[u8]) -> bool {
+        payload.len() > 8 && usize::from(u16::from_be_bytes([payload[4], payload[5]])) == payload.len() - 8
+    }
+
+    #[test]
+    fn link_layers() {
+        // linux cooked captures with and without tcp timestamps
+        let captures: [(&[u8], usize); 3] = [
+            (include_bytes!("./testdata/growatt_packet_T060119_66.bin"), 68),
+            (include_bytes!("./testdata/growatt_packet_T065103_67.bin"), 56),
+            (include_bytes!("./testdata/growatt_packet_T065104_267.bin"), 68),
+        ];
+        for (capture, offset) in captures {
+            let segment = parse(LinkType::LinuxSll, capture).unwrap();
+            assert_eq!(segment.offset, offset);
+            assert_eq!(segment.destination.1, 5279);
+            assert!(is_frame(segment.payload));
+        }
+
+        let capture = include_bytes!("./testdata/growatt_packet_T065104_267.bin");
+        let segment = parse(LinkType::LinuxSll, capture).unwrap();
+        assert_eq!(segment.source.0, IpAddr::V4(Ipv4Addr::new(172, 17, 0, 1)));
+        let tcp = &capture[36..68];
+
+        // the same segment in a vlan tagged ethernet frame
+        let mut ethernet = vec![0; 12];
+        ethernet.extend_from_slice(&[0x81, 0x00, 0x00, 0x05, 0x08, 0x00]);
+        ethernet.extend_from_slice(&capture[16..]);
+        let segment = parse(LinkType::Ethernet, &ethernet).unwrap();
+        assert_eq!(segment.offset, 70);
+        assert!(is_frame(segment.payload));
+
+        // and in an ipv6 packet with a hop by hop header, captured as sll2
+        let payload = &capture[68..];
+        let mut sll2 = vec![0x86, 0xdd];
+        sll2.resize(20, 0);
+        sll2.extend_from_slice(&[0x60, 0, 0, 0]);
+        sll2.extend_from_slice(&((8 + tcp.len() + payload.len()) as u16).to_be_bytes());
+        sll2.extend_from_slice(&[0, 64]);
+        sll2.extend_from_slice(&[0xfe, 0x80]);
+        sll2.resize(sll2.len() + 30, 1);
+        sll2.extend_from_slice(&[6, 0, 0, 0, 0, 0, 0, 0]);
+        sll2.extend_from_slice(tcp);
+        sll2.extend_from_slice(payload);
+        let segment = parse(LinkType::LinuxSll2, &sll2).unwrap();
+        assert_eq!(segment.offset, 20 + 40 + 8 + 32);
+        assert_eq!(segment.payload, payload);
+
+        assert!(parse(LinkType::Ethernet, &capture[..10]).is_err());
+
+        // an ipv4 header length below the minimum or beyond the total length
+        let mut invalid = capture.to_vec();
+        invalid[16] = 0x44;
+        assert!(parse(LinkType::LinuxSll, &invalid).is_err());
+        invalid[16] = 0x45;
+        invalid[18..20].copy_from_slice(&[0, 19]);
+        assert!(parse(LinkType::LinuxSll, &invalid).is_err());
+    }
+}
@@ -1,21 +1,61 @@
+use crate::command::{self, Command, Datalogger};
 use crate::dataprocessor::GrowattData;
-use crate::mqtt::{self, MqttConfig};
+use crate::firewall::{Action, FirewallPolicy};
+use crate::framing::FrameBuffer;
+use crate::layouts;
+use crate::message::{self, Direction, GrowattMessage};
+use crate::metrics::{self, Metrics};
+use crate::queue::FrameQueue;
+use crate::responder;
+use crate::sink::SinkRegistry;
 use crate::ProxyError;
 use log;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
+const REPLAY_REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+// commands without a response are forgotten after the timeout or once there are more than this many
+const MAX_PENDING_COMMANDS: usize = 16;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+// first packet index of our commands, away from the indices of the server commands
+const FIRST_COMMAND_INDEX: u16 = 0x8000;
+
+// A command sent to the inverter, the response repeats the packet index and the command key
+struct PendingCommand {
+    packet_index: u16,
+    key: (u8, u16),
+    sent: Instant,
+}
 
 pub struct GrowattProxyConfig {
     pub listen_address: String,
     pub growatt_address: String,
-    pub mqtt_address: Option<String>,
-    pub mqtt_port: u16,
+    pub sinks: SinkRegistry,
+    pub standalone: bool,
+    pub queue_dir: Option<PathBuf>,
+    /// The inverter is reported offline when no data is received for this long
+    pub inverter_timeout: Duration,
+    /// Address of the http server exposing the prometheus metrics
+    pub metrics_address: Option<String>,
+    /// Policy for the commands of the Growatt server, everything is forwarded when not set
+    pub firewall: Option<FirewallPolicy>,
 }
 
 pub struct GrowattProxy {
     address: String,
     growatt_address: String,
-    mqtt_config: Option<MqttConfig>,
+    sinks: SinkRegistry,
+    standalone: bool,
+    queue_dir: Option<PathBuf>,
+    inverter_timeout: Duration,
+    metrics_address: Option<String>,
+    firewall: Option<Arc<FirewallPolicy>>,
 }
 
 struct GrowattForwarder {
@@ -23,7 +63,7 @@ struct GrowattForwarder {
 }
 
 impl GrowattForwarder {
-    pub async fn new(address: String) -> Result<GrowattForwarder, ProxyError> {
+    pub async fn new(address: &str) -> Result<GrowattForwarder, ProxyError> {
         let addr = address.parse()?;
 
         let socket = TcpSocket::new_v4()?;
@@ -31,100 +71,632 @@ impl GrowattForwarder {
 
         Ok(GrowattForwarder { stream })
     }
+
+    // Sends a single frame and waits for the server to answer it
+    async fn send_and_wait_reply(&mut self, frame: &[u8]) -> Result<(), ProxyError> {
+        self.stream.write_all(frame).await?;
+
+        let mut buf = vec![0; 4096];
+        let mut frames = FrameBuffer::new();
+        while frames.next_frame().is_none() {
+            let n = tokio::time::timeout(REPLAY_REPLY_TIMEOUT, self.stream.read(&mut buf))
+                .await
+                .map_err(|_| ProxyError::NetworkError(String::from("Timeout waiting for server reply")))??;
+            if n == 0 {
+                return Err(ProxyError::NetworkError(String::from("Connection closed by server")));
+            }
+
+            frames.extend(&buf[..n]);
+        }
+
+        Ok(())
+    }
 }
 
-impl GrowattProxy {
-    pub fn new(cfg: GrowattProxyConfig) -> GrowattProxy {
-        let mqtt_config;
-        if let Some(addr) = cfg.mqtt_address {
-            log::info!("MQTT configuration: {}:{}", addr, cfg.mqtt_port);
-            mqtt_config = Some(MqttConfig {
-                server: addr,
-                port: cfg.mqtt_port,
-            });
-        } else {
-            mqtt_config = None;
+// Delivers the queued frames over a dedicated connection so the server replies do not reach the inverter
+async fn replay_queue(address: &str, queue: &FrameQueue) -> Result<(), ProxyError> {
+    let Some(_replay) = queue.start_replay() else {
+        return Ok(());
+    };
+
+    let frames = queue.frames()?;
+    if frames.is_empty() {
+        return Ok(());
+    }
+
+    log::info!("Replaying {} queued frames to the Growatt server", frames.len());
+    let mut forwarder = GrowattForwarder::new(address).await?;
+
+    // a frame leaves the queue only after the server replied to it
+    for frame in &frames {
+        forwarder.send_and_wait_reply(frame).await?;
+        queue.pop_front()?;
+    }
+
+    Ok(())
+}
+
+async fn connect_upstream(address: &str, queue: Option<&FrameQueue>) -> Option<GrowattForwarder> {
+    let forwarder = match GrowattForwarder::new(address).await {
+        Ok(forwarder) => forwarder,
+        Err(err) => {
+            log::warn!("Failed to connect to growatt server: {err}");
+            return None;
         }
+    };
 
-        GrowattProxy {
-            address: String::from(cfg.listen_address),
-            growatt_address: String::from(cfg.growatt_address),
-            mqtt_config,
+    if let Some(queue) = queue {
+        if let Err(err) = replay_queue(address, queue).await {
+            log::warn!("Failed to replay queued frames: {err}");
         }
     }
 
-    pub async fn run(self) -> Result<(), ProxyError> {
-        let listener = TcpListener::bind(&self.address).await?;
+    Some(forwarder)
+}
+
+async fn read_upstream(forwarder: &mut Option<GrowattForwarder>, buf: &mut [u8]) -> std::io::Result<usize> {
+    match forwarder {
+        Some(forwarder) => forwarder.stream.read(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Parses a frame received from the inverter and publishes the data, returns the data when it was published
+fn process_inverter_frame(
+    frame: &mut [u8],
+    message: Result<GrowattMessage, ProxyError>,
+    sinks: &SinkRegistry,
+) -> Option<GrowattData> {
+    log::debug!("Inverter frame: size {}", frame.len());
+    let message = match message {
+        Ok(message) => message,
+        Err(err) => {
+            if let ProxyError::CrcError(_) = err {
+                Metrics::increment(&metrics::metrics().crc_failures);
+            }
+
+            log::warn!("Invalid growatt data: {}", err);
+            return None;
+        }
+    };
+
+    if !message.has_data() {
+        Metrics::increment(&metrics::metrics().frames_parsed);
+        metrics::metrics().frame_ignored(&layouts::layout_id(frame));
+        match message {
+            GrowattMessage::Ping => log::debug!("Inverter ping"),
+            _ => log::info!("Inverter {message}: [#{}]", u16::from_be_bytes([frame[0], frame[1]])),
+        }
+
+        return None;
+    }
+
+    match GrowattData::from_buffer_auto_detect_layout(frame, None) {
+        Ok(data) => {
+            Metrics::increment(&metrics::metrics().frames_parsed);
+            if data.has_data() {
+                metrics::metrics().record_data(&data);
+                log::info!(
+                    "Growatt data: [#{}] {} -> {} (Buffered: {})",
+                    data.packet_index(),
+                    data.layout(),
+                    data.layout_spec,
+                    data.is_buffered()
+                );
+                sinks.publish(&data);
+
+                return Some(data);
+            }
+
+            metrics::metrics().frame_ignored(&data.layout());
+            log::info!(
+                "Growatt data ignored: [#{}] {} -> {} (Buffered: {})",
+                data.packet_index(),
+                data.layout(),
+                data.layout_spec,
+                data.is_buffered()
+            );
+        }
+        Err(err) => {
+            if let ProxyError::CrcError(_) = err {
+                Metrics::increment(&metrics::metrics().crc_failures);
+            }
+
+            log::warn!("Invalid growatt data: {}", err);
+        }
+    }
+
+    None
+}
+
+// A single inverter connection. The session is forwarded to the Growatt server unless the proxy runs standalone,
+// then every frame is answered locally. When a queue is configured the session also survives an unreachable
+// server: frames are acknowledged locally and stored until the server can be reached again.
+struct InverterSession {
+    socket: TcpStream,
+    // None in standalone mode
+    growatt_addr: Option<String>,
+    forwarder: Option<GrowattForwarder>,
+    sinks: SinkRegistry,
+    queue: Option<Arc<FrameQueue>>,
+    firewall: Option<Arc<FirewallPolicy>>,
+    inverter_timeout: Duration,
+    serial: Option<String>,
+    online: bool,
+    datalogger: Option<Datalogger>,
+    // commands received over mqtt, the sender is registered with the command router
+    commands: UnboundedReceiver<Command>,
+    command_sender: UnboundedSender<Command>,
+    // commands sent to the inverter that were not answered yet
+    pending_commands: Vec<PendingCommand>,
+    command_index: u16,
+}
+
+impl InverterSession {
+    async fn run(mut self) {
+        if let Some(addr) = &self.growatt_addr {
+            self.forwarder = connect_upstream(addr, self.queue.as_deref()).await;
+            if self.forwarder.is_none() && self.queue.is_none() {
+                log::warn!("Failed to connect to growatt server, data will not be forwarded");
+                return;
+            }
+        }
+
+        let mut buf = vec![0; 4096];
+        let mut growatt_buf = vec![0; 4096];
+        let mut inverter_frames = FrameBuffer::new();
+        // only complete frames are forwarded so the commands can be sent in between
+        let mut server_frames = FrameBuffer::new();
+
+        let mut reconnect = tokio::time::interval_at(Instant::now() + RECONNECT_INTERVAL, RECONNECT_INTERVAL);
+        let silence = tokio::time::sleep(self.inverter_timeout);
+        tokio::pin!(silence);
 
         loop {
-            let (mut socket, _) = listener.accept().await?;
-            socket.set_nodelay(true)?;
+            tokio::select! {
+                res = self.socket.read(&mut buf) => {
+                    let n = match res {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(err) => {
+                            log::warn!("Failed to read inverter data: {err}");
+                            break;
+                        }
+                    };
 
-            let growatt_addr = self.growatt_address.to_owned();
+                    log::debug!("Got inverter data: size {}", n);
+                    silence.as_mut().reset(Instant::now() + self.inverter_timeout);
 
-            let mqtt_config = self.mqtt_config.to_owned();
+                    // the inverter stream is forwarded as is, the frames are only cut out for parsing
+                    self.forward_inverter_data(&buf[..n]).await;
+                    inverter_frames.extend(&buf[..n]);
+                    if let Err(err) = self.handle_inverter_frames(&mut inverter_frames).await {
+                        log::warn!("Inverter session ended: {err}");
+                        break;
+                    }
+                }
 
-            tokio::spawn(async move {
-                log::info!("Inverter connected");
-                let mut buf = vec![0; 4096];
-                let mut growatt_buf = vec![0; 4096];
-
-                let mut growatt_data = Vec::new();
-
-                if let Ok(mut forwarder) = GrowattForwarder::new(growatt_addr).await {
-                    loop {
-                        tokio::select! {
-                            Ok(n) = socket.read(&mut buf) =>  {
-                                if n == 0 {
-                                    return;
-                                }
-
-                                log::debug!("Got inverter data: size {}", n);
-                                growatt_data.clear();
-                                growatt_data.extend_from_slice(&buf[..n]);
-
-                                if n > 128 {
-                                    match GrowattData::from_buffer_auto_detect_layout(&mut growatt_data, None) {
-                                        Ok(data) => {
-                                            if data.has_data() {
-                                                if let Some(cfg) = mqtt_config.as_ref() {
-                                                    log::info!("Growatt data: [#{}] {} -> {} (Buffered: {})", data.packet_index(), data.layout(), data.layout_spec, data.is_buffered());
-                                                    if let Err(err) = mqtt::publish_data(&data, &cfg).await {
-                                                        log::warn!("Failed to publish MQTT data: {err}");
-                                                    }
-                                                }
-                                            } else {
-                                                log::info!("Growatt data ignored: [#{}] {} -> {} (Buffered: {})", data.packet_index(), data.layout(), data.layout_spec, data.is_buffered());
-                                            }
-                                        }
-                                        Err(err) => log::warn!("Invalid growatt data: {}", err)
-                                    }
-                                }
-
-                                // Forward data to the growatt server if we are connected
-                                if let Err(err) = forwarder.stream.write_all(&buf[..n]).await {
-                                    log::warn!("Failed to forward data to Growatt server: {err}");
-                                    return;
-                                }
+                res = read_upstream(&mut self.forwarder, &mut growatt_buf) => {
+                    let n = match res {
+                        Ok(n) if n > 0 => n,
+                        res => {
+                            if let Err(err) = res {
+                                log::warn!("Failed to read from Growatt server: {err}");
                             }
 
-                            Ok(n) = forwarder.stream.read(&mut growatt_buf) => {
-                                log::debug!("Response from growatt: {}", n);
-                                if n == 0 {
-                                    return;
-                                }
-
-                                if let Err(err) = socket.write_all(&growatt_buf[..n]).await {
-                                    log::warn!("Failed to forward response from Growatt server: {err}");
-                                    return;
-                                }
+                            if self.queue.is_none() {
+                                break;
                             }
+
+                            log::warn!("Lost connection to the Growatt server, queueing inverter data");
+                            self.forwarder = None;
+                            continue;
                         }
+                    };
+
+                    server_frames.extend(&growatt_buf[..n]);
+                    if let Err(err) = self.forward_server_frames(&mut server_frames).await {
+                        log::warn!("Failed to forward response from Growatt server: {err}");
+                        break;
                     }
-                } else {
-                    log::warn!("Failed to connect to growatt server, data will not be forwarded");
+                }
+
+                _ = reconnect.tick(), if self.forwarder.is_none() && self.queue.is_some() => {
+                    if let Some(addr) = &self.growatt_addr {
+                        self.forwarder = connect_upstream(addr, self.queue.as_deref()).await;
+                    }
+                }
+
+                _ = &mut silence, if self.online => {
+                    log::warn!("No inverter data received for {}s", self.inverter_timeout.as_secs());
+                    self.set_online(false);
+                }
+
+                Some(command) = self.commands.recv() => {
+                    if let Err(err) = self.send_command(command).await {
+                        log::warn!("Failed to send command to the inverter: {err}");
+                        if let Some(serial) = &self.serial {
+                            self.sinks.command_response(serial, &serde_json::json!({ "error": err.to_string() }));
+                        }
+                    }
+                }
+            }
+        }
+
+        log::info!("Inverter disconnected");
+        self.set_online(false);
+        if let Some(serial) = &self.serial {
+            command::router().unregister(serial, &self.command_sender);
+        }
+    }
+
+    async fn forward_server_frames(&mut self, frames: &mut FrameBuffer) -> Result<(), ProxyError> {
+        while let Some(frame) = frames.next_frame() {
+            match GrowattMessage::decode(&frame, Direction::FromServer) {
+                Ok(message) => {
+                    let action = self
+                        .firewall
+                        .as_ref()
+                        .map_or(Action::Allow, |firewall| firewall.check(&message));
+                    match action {
+                        Action::Allow => log::debug!("Growatt server: {message}"),
+                        Action::Log => log::info!("Growatt server: {message}"),
+                        Action::Drop => {
+                            log::warn!("Blocked Growatt server command: {message}");
+                            Metrics::increment(&metrics::metrics().commands_blocked);
+                        }
+                    }
+
+                    if let Some(serial) = &self.serial {
+                        self.sinks.server_message(serial, &message, action != Action::Drop);
+                    }
+
+                    if action == Action::Drop {
+                        continue;
+                    }
+                }
+                Err(err) => {
+                    log::warn!("Failed to decode the Growatt server frame: {err}");
+                    if let Some(Action::Drop) = self
+                        .firewall
+                        .as_ref()
+                        .map(|firewall| firewall.check_undecodable(frame[7]))
+                    {
+                        log::warn!("Blocked undecodable Growatt server frame of type {:#04x}", frame[7]);
+                        Metrics::increment(&metrics::metrics().commands_blocked);
+                        continue;
+                    }
+                }
+            }
+
+            self.socket.write_all(&frame).await?;
+            Metrics::add(&metrics::metrics().bytes_to_inverter, frame.len());
+        }
+
+        Ok(())
+    }
+
+    async fn send_command(&mut self, command: Command) -> Result<(), ProxyError> {
+        let datalogger = self
+            .datalogger
+            .as_ref()
+            .ok_or_else(|| ProxyError::RuntimeError(String::from("Datalogger serial not known yet")))?;
+
+        self.command_index = self.command_index.wrapping_add(1);
+        let frame = command.frame(datalogger, self.command_index)?;
+        self.socket.write_all(&frame).await?;
+
+        log::info!("Sent command to datalogger {}: {command:?}", datalogger.serial);
+        self.expire_commands();
+        if self.pending_commands.len() >= MAX_PENDING_COMMANDS {
+            self.pending_commands.remove(0);
+        }
+        self.pending_commands.push(PendingCommand {
+            packet_index: self.command_index,
+            key: command.key(),
+            sent: Instant::now(),
+        });
+        Ok(())
+    }
+
+    // Forgets the commands the inverter did not answer in time
+    fn expire_commands(&mut self) {
+        let (expired, pending) = std::mem::take(&mut self.pending_commands)
+            .into_iter()
+            .partition(|pending| pending.sent.elapsed() >= COMMAND_TIMEOUT);
+        self.pending_commands = pending;
+
+        for command in expired {
+            log::warn!("No response to command #{} from the inverter", command.packet_index);
+            if let Some(serial) = &self.serial {
+                self.sinks.command_response(
+                    serial,
+                    &serde_json::json!({ "error": "No response from the inverter", "register": command.key.1 }),
+                );
+            }
+        }
+    }
+
+    async fn forward_inverter_data(&mut self, data: &[u8]) {
+        if let Some(forwarder) = self.forwarder.as_mut() {
+            match forwarder.stream.write_all(data).await {
+                Ok(()) => Metrics::add(&metrics::metrics().bytes_to_server, data.len()),
+                Err(err) => {
+                    log::warn!("Failed to forward data to Growatt server: {err}");
+                    self.forwarder = None;
+                }
+            }
+        }
+    }
+
+    // The responses to our own commands are published instead of being processed as inverter data
+    fn take_command_response(&mut self, frame: &[u8], message: &GrowattMessage) -> bool {
+        self.expire_commands();
+
+        let packet_index = u16::from_be_bytes([frame[0], frame[1]]);
+        let Some(index) = command::response_key(message).and_then(|key| {
+            self.pending_commands
+                .iter()
+                .position(|pending| pending.packet_index == packet_index && pending.key == key)
+        }) else {
+            return false;
+        };
+
+        self.pending_commands.remove(index);
+        log::info!("Inverter {message}");
+        if let Some(serial) = &self.serial {
+            self.sinks.command_response(serial, &command::response_json(message));
+        }
+
+        true
+    }
+
+    async fn handle_inverter_frames(&mut self, frames: &mut FrameBuffer) -> Result<(), ProxyError> {
+        while let Some(mut frame) = frames.next_frame() {
+            let message = GrowattMessage::decode(&frame, Direction::FromInverter);
+            if let Ok(message) = &message {
+                if self.datalogger.is_none() {
+                    self.datalogger = Datalogger::from_frame(&frame);
+                }
+
+                // the server sends its first commands right after the announce, before any data names the inverter
+                if let (GrowattMessage::Announce, None) = (message, &self.serial) {
+                    if let Some(serial) = message::announced_inverter_serial(&frame) {
+                        self.set_serial(serial);
+                    }
+                }
+
+                if self.take_command_response(&frame, message) {
+                    continue;
+                }
+            }
+
+            if self.forwarder.is_none() {
+                if self.growatt_addr.is_some() && self.queue.is_none() {
+                    return Err(ProxyError::NetworkError(String::from("Growatt server unreachable")));
+                }
+
+                if let Some(queue) = self.queue.as_deref() {
+                    if FrameQueue::should_queue(&frame) {
+                        queue.push(&frame)?;
+                    }
+                }
+
+                if let Some(reply) = responder::server_reply(&frame) {
+                    self.socket.write_all(&reply).await?;
+                }
+            }
+
+            if let Some(data) = process_inverter_frame(&mut frame, message, &self.sinks) {
+                if let (false, Some(serial)) = (data.is_smart_meter(), data.serial()) {
+                    self.set_serial(serial);
+                }
+
+                // the availability is published for the serial, a meter frame before the inverter data has none yet
+                if self.serial.is_some() {
+                    self.set_online(true);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // The inverter serial names the topics of the session and routes the commands to it
+    fn set_serial(&mut self, serial: String) {
+        if self.serial.as_ref() == Some(&serial) {
+            return;
+        }
+
+        if let Some(previous) = &self.serial {
+            command::router().unregister(previous, &self.command_sender);
+        }
+        command::router().register(&serial, self.command_sender.clone());
+        self.serial = Some(serial);
+    }
+
+    fn set_online(&mut self, online: bool) {
+        if self.online == online {
+            return;
+        }
+
+        self.online = online;
+        if let Some(serial) = &self.serial {
+            // the availability belongs to the latest session, a stale one must not report the inverter offline
+            if !command::router().is_registered(serial, &self.command_sender) {
+                log::debug!("Inverter {serial} reconnected, availability left to the new session");
+                return;
+            }
+
+            log::info!("Inverter {serial} {}", if online { "online" } else { "offline" });
+            self.sinks.inverter_availability(serial, online);
+        }
+    }
+}
+
+impl GrowattProxy {
+    pub fn new(cfg: GrowattProxyConfig) -> GrowattProxy {
+        GrowattProxy {
+            address: cfg.listen_address,
+            growatt_address: cfg.growatt_address,
+            sinks: cfg.sinks,
+            standalone: cfg.standalone,
+            queue_dir: cfg.queue_dir,
+            inverter_timeout: cfg.inverter_timeout,
+            metrics_address: cfg.metrics_address,
+            firewall: cfg.firewall.map(Arc::new),
+        }
+    }
+
+    pub async fn run(self) -> Result<(), ProxyError> {
+        let listener = TcpListener::bind(&self.address).await?;
+        if self.standalone {
+            log::info!("Standalone mode: inverter data is acknowledged locally and not forwarded");
+        }
+
+        if let Some(address) = self.metrics_address.clone() {
+            tokio::spawn(async move {
+                if let Err(err) = metrics::serve(&address).await {
+                    log::warn!("Metrics server stopped: {err}");
                 }
             });
         }
+
+        let queue = match &self.queue_dir {
+            Some(dir) if !self.standalone => Some(Arc::new(FrameQueue::new(dir)?)),
+            _ => None,
+        };
+
+        loop {
+            let (socket, _) = listener.accept().await?;
+            socket.set_nodelay(true)?;
+            Metrics::increment(&metrics::metrics().connections_accepted);
+
+            let growatt_addr = self.growatt_address.to_owned();
+
+            let sinks = self.sinks.clone();
+            let queue = queue.clone();
+
+            log::info!("Inverter connected");
+            let (command_sender, commands) = mpsc::unbounded_channel();
+            let session = InverterSession {
+                socket,
+                growatt_addr: (!self.standalone).then_some(growatt_addr),
+                forwarder: None,
+                sinks,
+                queue,
+                firewall: self.firewall.clone(),
+                inverter_timeout: self.inverter_timeout,
+                serial: None,
+                online: false,
+                datalogger: None,
+                commands,
+                command_sender,
+                pending_commands: Vec::new(),
+                command_index: FIRST_COMMAND_INDEX,
+            };
+
+            tokio::spawn(session.run());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    use super::{InverterSession, FIRST_COMMAND_INDEX};
+    use crate::{
+        dataprocessor::GrowattData,
+        framing::{GrowattFrame, MSG_READ_REGISTERS},
+        message::GrowattMessage,
+        sink::{DataSink, SinkRegistry},
+        ProxyError,
+    };
+
+    #[derive(Clone, Default)]
+    struct Collector {
+        server_messages: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl DataSink for Collector {
+        fn name(&self) -> &str {
+            "collector"
+        }
+
+        fn publish(&self, _data: &GrowattData) -> Result<(), ProxyError> {
+            Ok(())
+        }
+
+        fn server_message(&self, serial: &str, message: &GrowattMessage, _forwarded: bool) -> Result<(), ProxyError> {
+            let message = (String::from(serial), message.to_string());
+            self.server_messages.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn server_frames_after_the_announce() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut inverter = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let collector = Collector::default();
+        let mut sinks = SinkRegistry::new();
+        sinks.register(collector.clone());
+
+        let (command_sender, commands) = mpsc::unbounded_channel();
+        let session = tokio::spawn(
+            InverterSession {
+                socket,
+                growatt_addr: Some(server.local_addr().unwrap().to_string()),
+                forwarder: None,
+                sinks,
+                queue: None,
+                firewall: None,
+                inverter_timeout: Duration::from_secs(60),
+                serial: None,
+                online: false,
+                datalogger: None,
+                commands,
+                command_sender,
+                pending_commands: Vec::new(),
+                command_index: FIRST_COMMAND_INDEX,
+            }
+            .run(),
+        );
+        let (mut upstream, _) = server.accept().await.unwrap();
+
+        let announce = include_bytes!("./testdata/growatt_1.bin");
+        inverter.write_all(announce).await.unwrap();
+        let mut forwarded = vec![0; announce.len()];
+        upstream.read_exact(&mut forwarded).await.unwrap();
+
+        // the server reads the registers before the inverter sent any data
+        let read = GrowattFrame::new(MSG_READ_REGISTERS)
+            .serial("DLG0000001")
+            .payload(&[0, 3, 0, 4])
+            .build()
+            .unwrap();
+        upstream.write_all(&read).await.unwrap();
+        let mut forwarded = vec![0; read.len()];
+        inverter.read_exact(&mut forwarded).await.unwrap();
+
+        drop(inverter);
+        session.await.unwrap();
+
+        let messages = collector.server_messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, "MFK0CE306Q");
+        assert_eq!(
+            messages[0].1,
+            GrowattMessage::ReadRegisters { start: 3, end: 4 }.to_string()
+        );
     }
 }
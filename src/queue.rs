@@ -0,0 +1,180 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+    framing::{self, FrameBuffer, MSG_BUFFERED_DATA, MSG_BUFFERED_SMART_METER, MSG_DATA, MSG_SMART_METER},
+    ProxyError,
+};
+
+const QUEUE_FILE: &str = "growatt_queue.bin";
+
+/// On disk queue of inverter frames that could not be delivered to the Growatt server.
+/// Frames are stored back to back, the header length field is enough to split them again.
+pub struct FrameQueue {
+    path: PathBuf,
+    // held while the queue file is read or written, a frame pushed while the file is rewritten would be lost
+    file: std::sync::Mutex<()>,
+    // held while the queue is replayed, the sessions share the queue
+    replay: Mutex<()>,
+}
+
+impl FrameQueue {
+    pub fn new(dir: &Path) -> Result<FrameQueue, ProxyError> {
+        std::fs::create_dir_all(dir)?;
+
+        let queue = FrameQueue {
+            path: dir.join(QUEUE_FILE),
+            file: std::sync::Mutex::new(()),
+            replay: Mutex::new(()),
+        };
+
+        let pending = queue.len()?;
+        if pending > 0 {
+            log::info!("{pending} queued frames waiting for delivery");
+        }
+
+        Ok(queue)
+    }
+
+    /// Only data frames are worth storing, the rest of the conversation is meaningless later on
+    pub fn should_queue(frame: &[u8]) -> bool {
+        [MSG_DATA, MSG_BUFFERED_DATA, MSG_SMART_METER, MSG_BUFFERED_SMART_METER].contains(&frame[7])
+    }
+
+    pub fn push(&self, frame: &[u8]) -> Result<(), ProxyError> {
+        let _file = self.lock_file()?;
+        let mut file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        file.write_all(frame)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> Result<usize, ProxyError> {
+        let _file = self.lock_file()?;
+        Ok(self.load()?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool, ProxyError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The queued frames, marked as buffered and ready to be replayed. The frames stay in the queue until they are
+    /// removed with `pop_front`, so a replay that is interrupted does not lose them.
+    pub fn frames(&self) -> Result<Vec<Vec<u8>>, ProxyError> {
+        let mut frames = {
+            let _file = self.lock_file()?;
+            self.load()?
+        };
+        for frame in frames.iter_mut() {
+            mark_buffered(frame);
+        }
+
+        Ok(frames)
+    }
+
+    /// Claims the replay of the queue, None when another session is already replaying it
+    pub fn start_replay(&self) -> Option<MutexGuard<'_, ()>> {
+        self.replay.try_lock().ok()
+    }
+
+    /// Removes the oldest frame once the server confirmed it
+    pub fn pop_front(&self) -> Result<(), ProxyError> {
+        let _file = self.lock_file()?;
+        let frames = self.load()?;
+        self.write(frames.get(1..).unwrap_or_default())
+    }
+
+    fn lock_file(&self) -> Result<std::sync::MutexGuard<'_, ()>, ProxyError> {
+        self.file
+            .lock()
+            .map_err(|_| ProxyError::RuntimeError(String::from("Queue lock poisoned")))
+    }
+
+    // The caller holds the file lock
+    fn load(&self) -> Result<Vec<Vec<u8>>, ProxyError> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut buffer = FrameBuffer::new();
+        buffer.extend(&data);
+
+        let mut frames = Vec::new();
+        while let Some(frame) = buffer.next_frame() {
+            frames.push(frame);
+        }
+
+        Ok(frames)
+    }
+
+    // Replaces the queue file in one step, a crash while writing leaves the old file in place. The caller holds the
+    // file lock
+    fn write(&self, frames: &[Vec<u8>]) -> Result<(), ProxyError> {
+        let temp = self.path.with_extension("tmp");
+        std::fs::write(&temp, frames.concat())?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
+// The server expects replayed history to carry the buffered message type
+fn mark_buffered(frame: &mut [u8]) {
+    let buffered = match frame[7] {
+        MSG_SMART_METER | MSG_BUFFERED_SMART_METER => MSG_BUFFERED_SMART_METER,
+        _ => MSG_BUFFERED_DATA,
+    };
+    if frame[7] == buffered {
+        return;
+    }
+
+    frame[7] = buffered;
+    if framing::has_crc(frame) {
+        framing::update_crc(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameQueue;
+    use crate::dataprocessor::GrowattData;
+
+    #[test]
+    fn replay_marks_frames_buffered() {
+        let dir = std::env::temp_dir().join(format!("growattproxy_queue_{}", std::process::id()));
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        let mut frame = growatt_data.to_vec();
+        frame[7] = 0x04;
+        crate::framing::update_crc(&mut frame);
+
+        let queue = FrameQueue::new(&dir).unwrap();
+        let mut meter = growatt_data.to_vec();
+        meter[7] = 0x20;
+        crate::framing::update_crc(&mut meter);
+        assert!(FrameQueue::should_queue(&frame) && FrameQueue::should_queue(&meter));
+
+        queue.push(&frame).unwrap();
+        queue.push(&meter).unwrap();
+        assert_eq!(queue.len().unwrap(), 2);
+
+        let frames = queue.frames().unwrap();
+        assert_eq!(queue.len().unwrap(), 2);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0][7], 0x50);
+        assert_eq!(frames[1][7], 0x1b);
+        assert!(GrowattData::validate_integity(&frames[0]).is_ok());
+        assert!(GrowattData::validate_integity(&frames[1]).is_ok());
+
+        queue.pop_front().unwrap();
+        assert_eq!(queue.len().unwrap(), 1);
+        queue.pop_front().unwrap();
+        assert!(queue.is_empty().unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::{
+    framing::FrameBuffer,
+    packet::{TcpSegment, TCP_FIN, TCP_RST, TCP_SYN},
+};
+
+// Out of order data kept per flow while waiting for a missing segment, beyond that the gap is skipped
+const MAX_OUT_OF_ORDER_SIZE: usize = 64 * 1024;
+const MAX_FLOWS: usize = 64;
+
+type Endpoint = (IpAddr, u16);
+
+#[derive(Default)]
+struct Flow {
+    // sequence number of the next byte of the stream, unset until the first segment is seen
+    next_sequence: Option<u32>,
+    out_of_order: Vec<(u32, Vec<u8>)>,
+    frames: FrameBuffer,
+    last_seen: u64,
+}
+
+// Position of a sequence number relative to the next expected byte, handles the wrap around of the sequence numbers
+fn distance(sequence: u32, next: u32) -> i64 {
+    i64::from(sequence.wrapping_sub(next) as i32)
+}
+
+impl Flow {
+    // Appends the part of the data that was not seen yet, returns false when the data starts beyond a gap
+    fn append(&mut self, sequence: u32, data: &[u8]) -> bool {
+        let next = *self.next_sequence.get_or_insert(sequence);
+        let distance = distance(sequence, next);
+        if distance > 0 {
+            return false;
+        }
+
+        // retransmitted bytes are skipped
+        let seen = usize::try_from(-distance).unwrap_or(usize::MAX);
+        if seen < data.len() {
+            self.frames.extend(&data[seen..]);
+            self.next_sequence = Some(next.wrapping_add((data.len() - seen) as u32));
+        }
+
+        true
+    }
+
+    fn push(&mut self, sequence: u32, data: &[u8]) {
+        if !self.append(sequence, data) {
+            self.out_of_order.push((sequence, data.to_vec()));
+        }
+
+        loop {
+            let pending = std::mem::take(&mut self.out_of_order);
+            let count = pending.len();
+            for (sequence, data) in pending {
+                if !self.append(sequence, &data) {
+                    self.out_of_order.push((sequence, data));
+                }
+            }
+
+            if self.out_of_order.len() < count {
+                continue;
+            }
+
+            // a segment that was never captured, continue after the gap
+            if self.out_of_order.iter().map(|(_, data)| data.len()).sum::<usize>() <= MAX_OUT_OF_ORDER_SIZE {
+                break;
+            }
+            let Some(next) = self.next_sequence else {
+                break;
+            };
+            if let Some(sequence) = self
+                .out_of_order
+                .iter()
+                .map(|(sequence, _)| *sequence)
+                .min_by_key(|sequence| distance(*sequence, next))
+            {
+                log::warn!("Skipping {} missing bytes of the stream", distance(sequence, next));
+                self.next_sequence = Some(sequence);
+            }
+        }
+    }
+}
+
+/// Puts the captured TCP segments of each connection back in order and cuts the streams into Growatt frames
+#[derive(Default)]
+pub struct StreamReassembler {
+    flows: HashMap<(Endpoint, Endpoint), Flow>,
+    packets: u64,
+}
+
+impl StreamReassembler {
+    pub fn new() -> StreamReassembler {
+        StreamReassembler::default()
+    }
+
+    /// Adds a segment to its flow and returns the frames that are now complete
+    pub fn push(&mut self, segment: &TcpSegment) -> Vec<Vec<u8>> {
+        let key = (segment.source, segment.destination);
+        if segment.flags & TCP_RST != 0 {
+            self.flows.remove(&key);
+            return Vec::new();
+        }
+
+        self.packets += 1;
+        if !self.flows.contains_key(&key) && self.flows.len() >= MAX_FLOWS {
+            self.evict();
+        }
+
+        let flow = self.flows.entry(key).or_default();
+        flow.last_seen = self.packets;
+
+        let mut sequence = segment.sequence;
+        if segment.flags & TCP_SYN != 0 {
+            // a new connection, the syn takes up one sequence number
+            *flow = Flow {
+                last_seen: self.packets,
+                ..Flow::default()
+            };
+            sequence = sequence.wrapping_add(1);
+            flow.next_sequence = Some(sequence);
+        }
+
+        if !segment.payload.is_empty() {
+            flow.push(sequence, segment.payload);
+        }
+
+        let mut frames = Vec::new();
+        while let Some(frame) = flow.frames.next_frame() {
+            frames.push(frame);
+        }
+
+        if segment.flags & TCP_FIN != 0 {
+            self.flows.remove(&key);
+        }
+
+        frames
+    }
+
+    // Forgets the flow that has been idle the longest, connections are not always closed in the capture
+    fn evict(&mut self) {
+        if let Some(key) = self
+            .flows
+            .iter()
+            .min_by_key(|(_, flow)| flow.last_seen)
+            .map(|(key, _)| *key)
+        {
+            self.flows.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::StreamReassembler;
+    use crate::packet::{TcpSegment, TCP_FIN, TCP_SYN};
+
+    #[test]
+    fn reassemble_segments() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        let segment = |source: u16, sequence: u32, flags: u8, payload| TcpSegment {
+            source: (IpAddr::V4(Ipv4Addr::new(192, 168, 1, 19)), source),
+            destination: (IpAddr::V4(Ipv4Addr::new(192, 168, 1, 13)), 5279),
+            sequence,
+            flags,
+            offset: 0,
+            payload,
+        };
+
+        let mut streams = StreamReassembler::new();
+        let start = u32::MAX - 100;
+        assert!(streams.push(&segment(1024, start, TCP_SYN, &[])).is_empty());
+
+        // out of order over the sequence number wrap around, with a retransmission and an overlap
+        let base = start.wrapping_add(1);
+        let at = |offset: usize| base.wrapping_add(offset as u32);
+        assert!(streams
+            .push(&segment(1024, at(300), 0, &growatt_data[300..]))
+            .is_empty());
+        assert!(streams.push(&segment(1024, at(0), 0, &growatt_data[..200])).is_empty());
+        assert!(streams.push(&segment(1024, at(0), 0, &growatt_data[..200])).is_empty());
+        // a second connection does not mix with the first
+        assert!(streams.push(&segment(1025, 7, 0, &growatt_data[..250])).is_empty());
+        let frames = streams.push(&segment(1024, at(150), 0, &growatt_data[150..350]));
+        assert_eq!(frames, [growatt_data.to_vec()]);
+
+        // the retransmitted frame is not reported twice
+        assert!(streams
+            .push(&segment(1024, at(300), 0, &growatt_data[300..]))
+            .is_empty());
+
+        let frames = streams.push(&segment(1025, 257, TCP_FIN, &growatt_data[250..]));
+        assert_eq!(frames, [growatt_data.to_vec()]);
+    }
+}
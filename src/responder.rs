@@ -0,0 +1,55 @@
+use crate::dataprocessor::HEADER_SIZE;
+use crate::framing::{
+    GrowattFrame, MSG_ANNOUNCE, MSG_BUFFERED_DATA, MSG_BUFFERED_SMART_METER, MSG_DATA, MSG_PING, MSG_SMART_METER,
+};
+
+/// Builds the reply the Growatt server would send for a frame received from the inverter.
+/// Returns None for messages that the server does not acknowledge.
+pub fn server_reply(frame: &[u8]) -> Option<Vec<u8>> {
+    if frame.len() < HEADER_SIZE {
+        return None;
+    }
+
+    match frame[7] {
+        // the server echoes pings unmodified
+        MSG_PING => Some(frame.to_vec()),
+        MSG_ANNOUNCE | MSG_DATA | MSG_BUFFERED_DATA | MSG_SMART_METER | MSG_BUFFERED_SMART_METER => {
+            ack(&frame[..HEADER_SIZE])
+        }
+        _ => None,
+    }
+}
+
+// An ack repeats the header of the acknowledged frame with a single 0x00 status byte as payload
+fn ack(header: &[u8]) -> Option<Vec<u8>> {
+    match GrowattFrame::reply_to(header).payload(&[0x00]).build() {
+        Ok(reply) => Some(reply),
+        Err(err) => {
+            log::warn!("Failed to build ack: {err}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::server_reply;
+    use crate::dataprocessor::GrowattData;
+
+    #[test]
+    fn data_ack() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+
+        let reply = server_reply(growatt_data).unwrap();
+        assert_eq!(reply[0..4], growatt_data[0..4]);
+        assert_eq!(reply[6..8], growatt_data[6..8]);
+        assert_eq!(reply[8], b'G');
+        assert!(GrowattData::validate_integity(&reply).is_ok());
+
+        let mut meter = growatt_data.to_vec();
+        meter[7] = 0x20;
+        let reply = server_reply(&meter).unwrap();
+        assert_eq!(reply[7], 0x20);
+        assert!(GrowattData::validate_integity(&reply).is_ok());
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{SecondsFormat, Utc};
+use serde_json::{Map, Value};
+
+use crate::{
+    dataprocessor::GrowattData,
+    influx::{InfluxArgs, InfluxWriter},
+    message::GrowattMessage,
+    mqtt::{field_value_to_json_value, MqttArgs, MqttClient},
+    status::StatusChange,
+    ProxyError,
+};
+
+/// Receives every parsed inverter data. Sinks are called from the network loop so they must not block,
+/// slow outputs should hand the data over to a background task.
+pub trait DataSink: Send + Sync {
+    /// Name used in the log messages
+    fn name(&self) -> &str;
+
+    fn publish(&self, data: &GrowattData) -> Result<(), ProxyError>;
+
+    /// Whether the buffered data the inverter replays is published too. Outputs that keep the current state skip
+    /// it, the history would overwrite the latest values.
+    fn publishes_buffered(&self) -> bool {
+        false
+    }
+
+    /// Called when an inverter starts or stops sending data
+    fn inverter_availability(&self, _serial: &str, _online: bool) -> Result<(), ProxyError> {
+        Ok(())
+    }
+
+    /// Called when the decoded status of a device changes
+    fn status_changed(&self, _change: &StatusChange) -> Result<(), ProxyError> {
+        Ok(())
+    }
+
+    /// Called with the response of an inverter to a command, see `GrowattMessage::to_json`
+    fn command_response(&self, _serial: &str, _response: &Value) -> Result<(), ProxyError> {
+        Ok(())
+    }
+
+    /// Called for every frame the Growatt server sends to the inverter, `forwarded` is false when the firewall
+    /// dropped it
+    fn server_message(&self, _serial: &str, _message: &GrowattMessage, _forwarded: bool) -> Result<(), ProxyError> {
+        Ok(())
+    }
+}
+
+/// The set of enabled outputs, failures of a sink are logged and do not affect the others
+#[derive(Clone, Default)]
+pub struct SinkRegistry {
+    sinks: Vec<Arc<dyn DataSink>>,
+    // last status by device, shared by the clones of the registry
+    statuses: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl SinkRegistry {
+    pub fn new() -> SinkRegistry {
+        SinkRegistry::default()
+    }
+
+    pub fn register<S: DataSink + 'static>(&mut self, sink: S) {
+        log::info!("Output enabled: {}", sink.name());
+        self.sinks.push(Arc::new(sink));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    pub fn publish(&self, data: &GrowattData) {
+        for sink in &self.sinks {
+            if data.is_buffered() && !sink.publishes_buffered() {
+                continue;
+            }
+
+            if let Err(err) = sink.publish(data) {
+                log::warn!("Failed to publish data to {}: {err}", sink.name());
+            }
+        }
+
+        if let Some(change) = self.status_change(data) {
+            log::info!(
+                "Status of {} changed: {} -> {}",
+                change.serial,
+                change.previous.as_deref().unwrap_or("-"),
+                change.status
+            );
+            for sink in &self.sinks {
+                if let Err(err) = sink.status_changed(&change) {
+                    log::warn!("Failed to publish status change to {}: {err}", sink.name());
+                }
+            }
+        }
+    }
+
+    // Buffered data is old and does not change the status
+    fn status_change(&self, data: &GrowattData) -> Option<StatusChange> {
+        if data.is_buffered() {
+            return None;
+        }
+
+        let (serial, status) = (data.device_id()?, data.status()?);
+        let mut statuses = self.statuses.lock().ok()?;
+        let previous = statuses.insert(serial.clone(), status.clone());
+        if previous.as_ref() == Some(&status) {
+            return None;
+        }
+
+        Some(StatusChange {
+            serial,
+            previous,
+            status,
+        })
+    }
+
+    pub fn command_response(&self, serial: &str, response: &Value) {
+        for sink in &self.sinks {
+            if let Err(err) = sink.command_response(serial, response) {
+                log::warn!("Failed to publish command response to {}: {err}", sink.name());
+            }
+        }
+    }
+
+    pub fn server_message(&self, serial: &str, message: &GrowattMessage, forwarded: bool) {
+        for sink in &self.sinks {
+            if let Err(err) = sink.server_message(serial, message, forwarded) {
+                log::warn!("Failed to publish server message to {}: {err}", sink.name());
+            }
+        }
+    }
+
+    pub fn inverter_availability(&self, serial: &str, online: bool) {
+        for sink in &self.sinks {
+            if let Err(err) = sink.inverter_availability(serial, online) {
+                log::warn!("Failed to publish inverter availability to {}: {err}", sink.name());
+            }
+        }
+    }
+}
+
+/// Appends every parsed data as a single json line to a file
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn new(path: &Path) -> Result<FileSink, ProxyError> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+
+        Ok(FileSink { file: Mutex::new(file) })
+    }
+
+    fn write_line(&self, map: Map<String, Value>) -> Result<(), ProxyError> {
+        let mut line = Value::Object(map).to_string();
+        line.push('\n');
+
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| ProxyError::RuntimeError(String::from("Data log lock poisoned")))?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl DataSink for FileSink {
+    fn name(&self) -> &str {
+        "data log"
+    }
+
+    fn publishes_buffered(&self) -> bool {
+        true
+    }
+
+    fn publish(&self, data: &GrowattData) -> Result<(), ProxyError> {
+        let mut map = Map::new();
+        map.insert(
+            String::from("received"),
+            Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)),
+        );
+        map.insert(String::from("layout"), Value::from(data.layout()));
+        map.insert(String::from("buffered"), Value::from(data.is_buffered()));
+        for field in &data.fields {
+            if let Some(value) = field_value_to_json_value(&field.value, None) {
+                map.insert(field.name.clone(), value);
+            }
+        }
+
+        self.write_line(map)
+    }
+
+    fn status_changed(&self, change: &StatusChange) -> Result<(), ProxyError> {
+        let mut map = Map::new();
+        map.insert(
+            String::from("received"),
+            Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)),
+        );
+        map.insert(String::from("event"), Value::from("status"));
+        map.insert(String::from("serial"), Value::from(change.serial.as_str()));
+        map.insert(String::from("previous"), Value::from(change.previous.clone()));
+        map.insert(String::from("status"), Value::from(change.status.as_str()));
+
+        self.write_line(map)
+    }
+
+    fn server_message(&self, serial: &str, message: &GrowattMessage, forwarded: bool) -> Result<(), ProxyError> {
+        let mut map = Map::new();
+        map.insert(
+            String::from("received"),
+            Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)),
+        );
+        map.insert(String::from("event"), Value::from("server"));
+        map.insert(String::from("serial"), Value::from(serial));
+        map.insert(String::from("forwarded"), Value::from(forwarded));
+        map.insert(String::from("message"), message.to_json());
+
+        self.write_line(map)
+    }
+}
+
+/// The output command line options shared by the binaries
+#[derive(clap::Args, Debug)]
+pub struct SinkArgs {
+    #[clap(flatten)]
+    pub mqtt: MqttArgs,
+
+    #[clap(flatten)]
+    pub influx: InfluxArgs,
+
+    // append every parsed inverter data as a json line to this file
+    #[clap(long = "data-log", env = "GP_DATA_LOG")]
+    pub data_log: Option<PathBuf>,
+}
+
+impl SinkArgs {
+    /// Starts the configured outputs, see `MqttArgs::config` for the MQTT defaults
+    pub fn registry(&self, default_topic: &str, default_payload: &str) -> Result<SinkRegistry, ProxyError> {
+        let mut sinks = SinkRegistry::new();
+
+        if let Some(cfg) = self.mqtt.config(default_topic, default_payload)? {
+            log::info!("MQTT configuration: {}:{} ({})", cfg.server, cfg.port, cfg.topic);
+            sinks.register(MqttClient::new(&cfg)?);
+        }
+
+        if let Some(cfg) = self.influx.config()? {
+            sinks.register(InfluxWriter::new(&cfg)?);
+        }
+
+        if let Some(path) = &self.data_log {
+            sinks.register(FileSink::new(path)?);
+        }
+
+        Ok(sinks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{DataSink, FileSink, SinkRegistry};
+    use crate::{dataprocessor::GrowattData, layouts, ProxyError};
+
+    #[derive(Clone, Default)]
+    struct Collector {
+        serials: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl DataSink for Collector {
+        fn name(&self) -> &str {
+            "collector"
+        }
+
+        fn publish(&self, data: &GrowattData) -> Result<(), ProxyError> {
+            self.serials.lock().unwrap().push(data.serial().unwrap_or_default());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn publish_to_all_sinks() {
+        let path = std::env::temp_dir().join(format!("growattproxy_sink_{}.json", std::process::id()));
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        let mut data = growatt_data.to_vec();
+        let gd = GrowattData::from_buffer(&mut data, &layouts::t065004x()).unwrap();
+
+        let collector = Collector::default();
+        let mut sinks = SinkRegistry::new();
+        sinks.register(FileSink::new(&path).unwrap());
+        sinks.register(collector.clone());
+
+        sinks.publish(&gd);
+        sinks.publish(&gd);
+
+        // buffered data only goes to the sinks that keep the history
+        let mut buffered = growatt_data.to_vec();
+        buffered[7] = 0x50;
+        crate::framing::update_crc(&mut buffered);
+        sinks.publish(&GrowattData::from_buffer(&mut buffered, &layouts::t065004x()).unwrap());
+
+        assert_eq!(*collector.serials.lock().unwrap(), ["MFK0CE306Q", "MFK0CE306Q"]);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        // the second data does not change the status
+        assert_eq!(log.lines().count(), 4);
+        assert!(log.contains(r#""buffered":true"#));
+        assert!(log.contains(r#""event":"status","serial":"MFK0CE306Q","previous":null,"status":"Normal""#));
+        assert!(log.contains(r#""layout":"T065103","buffered":false,"pvserial":"MFK0CE306Q""#));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
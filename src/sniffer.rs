@@ -1,25 +1,32 @@
 use crate::{
     dataprocessor::{FieldValue, GrowattData},
-    mqtt::{self, MqttConfig},
+    message,
+    packet::{self, LinkType},
+    reassembly::StreamReassembler,
+    sink::SinkRegistry,
+    ProxyError,
 };
 
-use std::path::PathBuf;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
 
 pub struct GrowattSnifferConfig {
     pub address: String,
     pub port: u16,
-    pub mqtt: Option<MqttConfig>,
+    pub sinks: SinkRegistry,
     pub dump_packets: bool,
 }
 
-fn process_data(data: &GrowattData, cfg: &GrowattSnifferConfig, offset: u16) {
+fn process_data(data: &GrowattData, sinks: &SinkRegistry, source: (IpAddr, u16)) {
     log::info!(
         "[{}] valid growatt data buffered: {} [{} -> {}] ({})",
         data.packet_index(),
         data.is_buffered(),
         data.layout(),
         data.layout_spec,
-        offset,
+        SocketAddr::from(source),
     );
 
     if !data.has_data() {
@@ -48,58 +55,71 @@ fn process_data(data: &GrowattData, cfg: &GrowattSnifferConfig, offset: u16) {
         }
     }
 
-    if (!data.is_buffered()) && cfg.mqtt.is_some() {
-        if let Err(err) = mqtt::publish_data_sync(&data, cfg.mqtt.as_ref().unwrap()) {
-            log::warn!("Failed to publish MQTT data: {err}");
-        }
+    sinks.publish(data);
+}
+
+fn dump_packet(data: &[u8], path: &str) {
+    if let Err(err) = crate::dump_packet(data, PathBuf::from(path).as_ref()) {
+        log::warn!("Failed to dump packet to {path}: {err}");
     }
 }
 
-pub fn sniff(cfg: &GrowattSnifferConfig) {
-    let mut cap = pcap::Capture::from_device("any")
-        .unwrap()
-        .immediate_mode(true)
-        .open()
-        .unwrap();
+pub fn sniff(cfg: &GrowattSnifferConfig) -> Result<(), ProxyError> {
+    let mut cap = pcap::Capture::from_device("any")?.immediate_mode(true).open()?;
+
+    cap.filter(format!("host {} and tcp", cfg.address).as_str(), true)?;
+    cap.filter(format!("dst port {}", cfg.port).as_str(), true)?;
 
-    cap.filter(format!("host {} and tcp", cfg.address).as_str(), true)
-        .unwrap();
-    cap.filter(format!("dst port {}", cfg.port).as_str(), true).unwrap();
+    let datalink = cap.get_datalink();
+    let link = LinkType::from_dlt(datalink.0)
+        .ok_or_else(|| ProxyError::RuntimeError(format!("Unsupported link type {}", datalink.0)))?;
 
+    let mut streams = StreamReassembler::new();
     let mut index = 1;
     let mut dump_index = 1;
     while let Ok(packet) = cap.next_packet() {
         log::debug!("got packet: {} {}", packet.header.len, packet.data.len());
-        if packet.data.len() > 128 {
-            let mut data = Vec::from(packet.data);
-            if let Ok(parsed_data) = GrowattData::from_buffer_auto_detect_layout(&mut data[56..], None) {
-                process_data(&parsed_data, cfg, 56);
-                if cfg.dump_packets {
-                    let path = PathBuf::from(format!(
-                        "/data/growatt_packet_{}_{}.bin",
-                        parsed_data.layout(),
-                        dump_index
-                    ));
-                    crate::dump_packet(&data, path.as_ref());
-                    dump_index += 1;
+        let segment = match packet::parse(link, packet.data) {
+            Ok(segment) => segment,
+            Err(err) => {
+                log::debug!("Skipping packet: {err}");
+                continue;
+            }
+        };
+
+        for frame in streams.push(&segment) {
+            // pings and acks do not contain power data
+            if !message::carries_data(frame[7]) {
+                continue;
+            }
+
+            // the captured packet is dumped when it holds the whole frame, like the packets in the testdata, a frame
+            // reassembled from several segments is dumped as received. Parsing decrypts, so it works on a copy
+            let raw = if segment.payload == frame.as_slice() {
+                packet.data
+            } else {
+                frame.as_slice()
+            };
+            let mut data = frame.clone();
+            match GrowattData::from_buffer_auto_detect_layout(&mut data, None) {
+                Ok(parsed_data) => {
+                    process_data(&parsed_data, &cfg.sinks, segment.source);
+                    if cfg.dump_packets {
+                        dump_packet(
+                            raw,
+                            &format!("/data/growatt_packet_{}_{}.bin", parsed_data.layout(), dump_index),
+                        );
+                        dump_index += 1;
+                    }
                 }
-            } else if let Ok(parsed_data) = GrowattData::from_buffer_auto_detect_layout(&mut data[68..], None) {
-                process_data(&parsed_data, cfg, 68);
-                if cfg.dump_packets {
-                    let path = PathBuf::from(format!(
-                        "/data/growatt_packet_{}_{}.bin",
-                        parsed_data.layout(),
-                        dump_index
-                    ));
-                    crate::dump_packet(&data, path.as_ref());
-                    dump_index += 1;
+                Err(err) => {
+                    log::warn!("invalid growatt data: {err}");
+                    dump_packet(raw, &format!("/data/growatt_invalid_{index}.bin"));
+                    index += 1;
                 }
-            } else {
-                log::warn!("invalid growatt data");
-                let path = PathBuf::from(format!("/data/growatt_invalid_{index}.bin"));
-                crate::dump_packet(&packet.data, path.as_ref());
-                index += 1;
             }
         }
     }
+
+    Ok(())
 }
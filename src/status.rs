@@ -0,0 +1,92 @@
+// Decoding tables for the inverter status, fault and warning registers
+
+const INVERTER_STATES: &[(i64, &str)] = &[(0, "Waiting"), (1, "Normal"), (3, "Fault")];
+
+const STORAGE_STATES: &[(i64, &str)] = &[
+    (0, "Waiting"),
+    (1, "Self test"),
+    (3, "Fault"),
+    (4, "Upgrading"),
+    (5, "PV and battery online"),
+    (6, "Battery online"),
+    (7, "PV offline mode"),
+    (8, "Battery offline mode"),
+];
+
+const FAULT_CODES: &[(i64, &str)] = &[
+    (0, "No fault"),
+    (24, "Auto test failed"),
+    (25, "No AC connection"),
+    (26, "PV isolation low"),
+    (27, "Residual current high"),
+    (28, "Output DC current high"),
+    (29, "PV voltage high"),
+    (30, "AC voltage out of range"),
+    (31, "AC frequency out of range"),
+    (32, "Module temperature high"),
+];
+
+const WARNING_CODES: &[(i64, &str)] = &[
+    (0, "No warning"),
+    (200, "String fault"),
+    (201, "String PID configuration warning"),
+    (203, "PV1 or PV2 short circuit"),
+    (205, "PV1 or PV2 boost driver broken"),
+    (207, "USB over current"),
+    (208, "Fuse broken"),
+    (400, "Fan warning"),
+    (401, "Meter communication abnormal"),
+    (402, "Optimizer and inverter communication abnormal"),
+    (404, "EEPROM abnormal"),
+];
+
+fn lookup(table: &[(i64, &str)], value: i64) -> Option<String> {
+    table
+        .iter()
+        .find(|(code, _)| *code == value)
+        .map(|(_, name)| String::from(*name))
+}
+
+/// Name of the operating state in the `pvstatus` register, the storage inverters have their own states
+pub fn status_text(value: i64, storage: bool) -> String {
+    let table = if storage { STORAGE_STATES } else { INVERTER_STATES };
+    lookup(table, value).unwrap_or_else(|| format!("Unknown ({value})"))
+}
+
+pub fn fault_text(code: i64) -> String {
+    match code {
+        // the generic errors are shown as "Error 1xx" on the inverter display
+        1..=23 => format!("Error {}", 99 + code),
+        _ => lookup(FAULT_CODES, code).unwrap_or_else(|| format!("Error {code}")),
+    }
+}
+
+pub fn warning_text(code: i64) -> String {
+    lookup(WARNING_CODES, code).unwrap_or_else(|| format!("Warning {code}"))
+}
+
+/// A change of the decoded inverter status, `previous` is unset for the first status seen
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatusChange {
+    pub serial: String,
+    pub previous: Option<String>,
+    pub status: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fault_text, status_text, warning_text};
+
+    #[test]
+    fn decode_codes() {
+        assert_eq!(status_text(1, false), "Normal");
+        assert_eq!(status_text(5, true), "PV and battery online");
+        assert_eq!(status_text(5, false), "Unknown (5)");
+        assert_eq!(fault_text(0), "No fault");
+        assert_eq!(fault_text(17), "Error 116");
+        assert_eq!(fault_text(26), "PV isolation low");
+        assert_eq!(fault_text(90), "Error 90");
+        assert_eq!(warning_text(400), "Fan warning");
+        assert_eq!(warning_text(12), "Warning 12");
+    }
+}
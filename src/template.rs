@@ -0,0 +1,182 @@
+use serde_json::{Map, Number, Value};
+
+use crate::{dataprocessor::GrowattData, mqtt::field_value_to_json_value, ProxyError};
+
+#[derive(Clone, Debug)]
+pub enum TemplateValue {
+    Constant(Value),
+    Field {
+        name: String,
+        scale: Option<f64>,
+        default: Option<Value>,
+    },
+}
+
+/// Describes how the published payload is built from the parsed inverter data
+#[derive(Clone, Debug)]
+pub enum PayloadTemplate {
+    /// Every parsed field using its own name
+    Fields,
+    /// Output keys mapped to constants or to parsed fields
+    Mapped(Vec<(String, TemplateValue)>),
+}
+
+impl PayloadTemplate {
+    /// Resolves a built-in preset ("fields" or "remi") or loads a json template file
+    pub fn load(name: &str) -> Result<PayloadTemplate, ProxyError> {
+        match name {
+            "fields" => Ok(PayloadTemplate::Fields),
+            "remi" => Ok(PayloadTemplate::remi()),
+            path => {
+                let json = std::fs::read_to_string(path)?;
+                PayloadTemplate::from_json(&json)
+                    .map_err(|err| ProxyError::RuntimeError(format!("Invalid payload template '{path}': {err}")))
+            }
+        }
+    }
+
+    /// A template is a json object: values that are objects with a "field" member are replaced by the parsed
+    /// value of that field (optionally multiplied by "scale", "default" is used when the field is missing),
+    /// all other values are published as is. String constants can contain placeholders, see `expand`.
+    pub fn from_json(json: &str) -> Result<PayloadTemplate, ProxyError> {
+        let value: Value =
+            serde_json::from_str(json).map_err(|err| ProxyError::RuntimeError(format!("Invalid json: {err}")))?;
+
+        let Value::Object(map) = value else {
+            return Err(ProxyError::RuntimeError(String::from("Template must be a json object")));
+        };
+
+        let mut entries = Vec::new();
+        for (key, value) in map {
+            let entry = match value {
+                Value::Object(ref field) if field.contains_key("field") => {
+                    let Some(Value::String(name)) = field.get("field") else {
+                        return Err(ProxyError::RuntimeError(format!(
+                            "'{key}': field name must be a string"
+                        )));
+                    };
+
+                    let scale = match field.get("scale") {
+                        Some(scale) => Some(
+                            scale
+                                .as_f64()
+                                .ok_or_else(|| ProxyError::RuntimeError(format!("'{key}': scale must be a number")))?,
+                        ),
+                        None => None,
+                    };
+
+                    TemplateValue::Field {
+                        name: name.clone(),
+                        scale,
+                        default: field.get("default").cloned(),
+                    }
+                }
+                value => TemplateValue::Constant(value),
+            };
+
+            entries.push((key, entry));
+        }
+
+        Ok(PayloadTemplate::Mapped(entries))
+    }
+
+    /// The payload format of the original remi energy monitor integration
+    pub fn remi() -> PayloadTemplate {
+        fn constant(key: &str, value: Value) -> (String, TemplateValue) {
+            (String::from(key), TemplateValue::Constant(value))
+        }
+
+        fn field(key: &str, name: &str, scale: Option<f64>, default: Option<Value>) -> (String, TemplateValue) {
+            (
+                String::from(key),
+                TemplateValue::Field {
+                    name: String::from(name),
+                    scale,
+                    default,
+                },
+            )
+        }
+
+        let zero = || Some(Value::Number(Number::from(0)));
+
+        PayloadTemplate::Mapped(Vec::from([
+            constant("ident", Value::String(String::from("pvpanelendak"))),
+            constant("device_CH", Value::Number(Number::from(1))),
+            constant("Name", Value::String(String::from("PV"))),
+            constant("CHname", Value::String(String::from("PV"))),
+            constant("Type", Value::String(String::from("MB"))),
+            constant("Units", Value::String(String::from("kWh"))),
+            field("U", "pvgridvoltage", None, None),
+            field("I", "pvgridcurrent", Some(1000.0), None),
+            field("P", "pvpowerout", None, None),
+            constant("HC", Value::Number(Number::from(0))),
+            field("DC", "pvenergytoday", Some(1000.0), zero()),
+            constant("MC", Value::Number(Number::from(0))),
+            field("CH", "pvenergytotal", Some(1000.0), zero()),
+            constant("CL", Value::Number(Number::from(0))),
+        ]))
+    }
+
+    pub fn render(&self, data: &GrowattData) -> String {
+        let mut map = Map::new();
+
+        match self {
+            PayloadTemplate::Fields => {
+                for field in &data.fields {
+                    if let Some(field_val) = field_value_to_json_value(&field.value, None) {
+                        map.insert(field.name.clone(), field_val);
+                    }
+                }
+            }
+            PayloadTemplate::Mapped(entries) => {
+                for (key, entry) in entries {
+                    let value = match entry {
+                        TemplateValue::Constant(Value::String(str)) => Some(Value::String(expand(str, data))),
+                        TemplateValue::Constant(value) => Some(value.clone()),
+                        TemplateValue::Field { name, scale, default } => data
+                            .field_value(name)
+                            .and_then(|val| field_value_to_json_value(&val, *scale))
+                            .or_else(|| default.clone()),
+                    };
+
+                    if let Some(value) = value {
+                        map.insert(key.clone(), value);
+                    }
+                }
+            }
+        }
+
+        Value::Object(map).to_string()
+    }
+}
+
+/// Replaces the {serial} and {layout} placeholders with the inverter serial and the detected layout
+pub fn expand(pattern: &str, data: &GrowattData) -> String {
+    let serial = data.serial().unwrap_or_else(|| String::from("unknown"));
+
+    pattern.replace("{serial}", &serial).replace("{layout}", &data.layout())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PayloadTemplate;
+    use crate::{dataprocessor::GrowattData, layouts};
+
+    #[test]
+    fn render_template() {
+        let growatt_data = include_bytes!("./testdata/growatt_1.bin");
+        let mut data = growatt_data.to_vec();
+        let gd = GrowattData::from_buffer(&mut data, &layouts::t065004x()).unwrap();
+
+        let template = PayloadTemplate::from_json(
+            r#"{"id": "pv_{serial}", "status": {"field": "pvstatus"}, "scaled": {"field": "pvstatus", "scale": 1000},
+                "missing": {"field": "nosuchfield", "default": -1}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            template.render(&gd),
+            r#"{"id":"pv_MFK0CE306Q","status":1,"scaled":1000.0,"missing":-1}"#
+        );
+    }
+}